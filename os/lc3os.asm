@@ -0,0 +1,215 @@
+; LC-3 operating system image bundled with the VM.
+;
+; It fills system space: the trap vector table at x0000, the interrupt vector
+; table at x0100 and the code below from x0200. Booting at OS_START switches to
+; the user program at x3000 in user mode.
+;
+; The VM boots it with traps entered like exceptions, pushing the PSR and PC
+; on the supervisor stack whether they come from the user program or from
+; supervisor code, so every routine returns with RTI. Routines never TRAP themselves;
+; they share the WRITE_CHAR and WRITE_STRING subroutines instead.
+
+            .ORIG x0000
+
+; Trap vector table
+            .BLKW x20 BAD_TRAP
+            .FILL TRAP_GETC         ; x20
+            .FILL TRAP_OUT          ; x21
+            .FILL TRAP_PUTS         ; x22
+            .FILL TRAP_IN           ; x23
+            .FILL TRAP_PUTSP        ; x24
+            .FILL TRAP_HALT         ; x25
+            .BLKW xDA BAD_TRAP
+
+; Interrupt vector table
+            .FILL EXC_PRIVILEGE     ; x00
+            .FILL EXC_ILLEGAL       ; x01
+            .FILL EXC_ACV           ; x02
+            .BLKW xFD BAD_INT
+
+; Boot: build the RTI frame that enters the user program
+OS_START    LD R6, OS_SP
+            LD R0, USER_PSR
+            ADD R6, R6, #-1
+            STR R0, R6, #0
+            LD R0, USER_PC
+            ADD R6, R6, #-1
+            STR R0, R6, #0
+            AND R0, R0, #0
+            RTI
+
+OS_SP       .FILL x3000
+USER_PSR    .FILL x8002
+USER_PC     .FILL x3000
+
+OS_KBSR     .FILL xFE00
+OS_KBDR     .FILL xFE02
+OS_MCR      .FILL xFFFE
+
+; GETC: reads a character into R0 without echoing it
+TRAP_GETC   LDI R0, OS_KBSR
+            BRzp TRAP_GETC
+            LDI R0, OS_KBDR
+            RTI
+
+; OUT: writes the character in R0
+TRAP_OUT    ST R7, OUT_SAVE_R7
+            JSR WRITE_CHAR
+            LD R7, OUT_SAVE_R7
+            RTI
+
+OUT_SAVE_R7 .FILL x0000
+
+; PUTS: writes the string of one character per word starting at R0
+TRAP_PUTS   ST R7, PUTS_SAVE_R7
+            JSR WRITE_STRING
+            LD R7, PUTS_SAVE_R7
+            RTI
+
+PUTS_SAVE_R7 .FILL x0000
+
+; IN: prompts for a character, echoes it and returns it in R0
+TRAP_IN     ST R1, IN_SAVE_R1
+            ST R7, IN_SAVE_R7
+            LEA R0, IN_PROMPT
+            JSR WRITE_STRING
+IN_POLL     LDI R0, OS_KBSR
+            BRzp IN_POLL
+            LDI R0, OS_KBDR
+            JSR WRITE_CHAR
+            ADD R1, R0, #0
+            LD R0, NEWLINE
+            JSR WRITE_CHAR
+            ADD R0, R1, #0
+            LD R1, IN_SAVE_R1
+            LD R7, IN_SAVE_R7
+            RTI
+
+IN_SAVE_R1  .FILL x0000
+IN_SAVE_R7  .FILL x0000
+NEWLINE     .FILL x000A
+IN_PROMPT   .STRINGZ "\nInput a character> "
+
+; PUTSP: writes the string of two characters per word starting at R0, low
+; byte first
+TRAP_PUTSP  ST R0, PUTSP_SAVE_R0
+            ST R1, PUTSP_SAVE_R1
+            ST R2, PUTSP_SAVE_R2
+            ST R3, PUTSP_SAVE_R3
+            ST R4, PUTSP_SAVE_R4
+            ST R5, PUTSP_SAVE_R5
+            ST R7, PUTSP_SAVE_R7
+            ADD R1, R0, #0
+PUTSP_LOOP  LDR R2, R1, #0
+            BRz PUTSP_DONE
+            LD R3, LOW_BYTE
+            AND R0, R2, R3
+            JSR WRITE_CHAR
+            ; There is no right shift, so copy bits 15:8 down one at a time
+            LD R3, BIT_EIGHT
+            AND R0, R0, #0
+            AND R4, R4, #0
+            ADD R4, R4, #1
+PUTSP_BIT   AND R5, R2, R3
+            BRz PUTSP_SHIFT
+            ADD R0, R0, R4
+PUTSP_SHIFT ADD R4, R4, R4
+            ADD R3, R3, R3
+            BRnp PUTSP_BIT
+            ADD R0, R0, #0
+            BRz PUTSP_DONE
+            JSR WRITE_CHAR
+            ADD R1, R1, #1
+            BR PUTSP_LOOP
+PUTSP_DONE  LD R0, PUTSP_SAVE_R0
+            LD R1, PUTSP_SAVE_R1
+            LD R2, PUTSP_SAVE_R2
+            LD R3, PUTSP_SAVE_R3
+            LD R4, PUTSP_SAVE_R4
+            LD R5, PUTSP_SAVE_R5
+            LD R7, PUTSP_SAVE_R7
+            RTI
+
+PUTSP_SAVE_R0 .FILL x0000
+PUTSP_SAVE_R1 .FILL x0000
+PUTSP_SAVE_R2 .FILL x0000
+PUTSP_SAVE_R3 .FILL x0000
+PUTSP_SAVE_R4 .FILL x0000
+PUTSP_SAVE_R5 .FILL x0000
+PUTSP_SAVE_R7 .FILL x0000
+LOW_BYTE    .FILL x00FF
+BIT_EIGHT   .FILL x0100
+
+; HALT: prints the halt message and stops the clock by clearing MCR[15].
+; If the clock is restarted the program continues after the TRAP.
+TRAP_HALT   ST R0, HALT_SAVE_R0
+            ST R1, HALT_SAVE_R1
+            ST R7, HALT_SAVE_R7
+            LEA R0, HALT_MESSAGE
+            JSR WRITE_STRING
+            LDI R1, OS_MCR
+            LD R0, CLOCK_MASK
+            AND R0, R1, R0
+            STI R0, OS_MCR
+            LD R0, HALT_SAVE_R0
+            LD R1, HALT_SAVE_R1
+            LD R7, HALT_SAVE_R7
+            RTI
+
+HALT_SAVE_R0 .FILL x0000
+HALT_SAVE_R1 .FILL x0000
+HALT_SAVE_R7 .FILL x0000
+CLOCK_MASK  .FILL x7FFF
+HALT_MESSAGE .STRINGZ "\n\n--- Halting the LC-3 ---\n\n"
+
+; Exceptions and undefined traps report what happened and halt
+EXC_PRIVILEGE LEA R0, PRIVILEGE_MESSAGE
+            BR EXC_HALT
+EXC_ILLEGAL LEA R0, ILLEGAL_MESSAGE
+            BR EXC_HALT
+EXC_ACV     LEA R0, ACV_MESSAGE
+            BR EXC_HALT
+BAD_TRAP    LEA R0, BAD_TRAP_MESSAGE
+EXC_HALT    JSR WRITE_STRING
+            BR TRAP_HALT
+
+; Interrupts without a handler are ignored
+BAD_INT     RTI
+
+PRIVILEGE_MESSAGE .STRINGZ "\n\n--- Privilege mode violation ---\n"
+ILLEGAL_MESSAGE .STRINGZ "\n\n--- Illegal opcode ---\n"
+ACV_MESSAGE .STRINGZ "\n\n--- Access control violation ---\n"
+BAD_TRAP_MESSAGE .STRINGZ "\n\n--- Undefined trap executed ---\n"
+
+; Writes the character in R0 once the display is ready
+WRITE_CHAR  ST R1, CHAR_SAVE_R1
+WRITE_POLL  LDI R1, WRITE_DSR
+            BRzp WRITE_POLL
+            STI R0, WRITE_DDR
+            LD R1, CHAR_SAVE_R1
+            RET
+
+CHAR_SAVE_R1 .FILL x0000
+
+; Writes the null-terminated string starting at R0
+WRITE_STRING ST R0, WRITE_SAVE_R0
+            ST R1, WRITE_SAVE_R1
+            ST R7, WRITE_SAVE_R7
+            ADD R1, R0, #0
+WRITE_LOOP  LDR R0, R1, #0
+            BRz WRITE_DONE
+            JSR WRITE_CHAR
+            ADD R1, R1, #1
+            BR WRITE_LOOP
+WRITE_DONE  LD R0, WRITE_SAVE_R0
+            LD R1, WRITE_SAVE_R1
+            LD R7, WRITE_SAVE_R7
+            RET
+
+WRITE_SAVE_R0 .FILL x0000
+WRITE_SAVE_R1 .FILL x0000
+WRITE_SAVE_R7 .FILL x0000
+WRITE_DSR   .FILL xFE04
+WRITE_DDR   .FILL xFE06
+
+            .END
@@ -0,0 +1,746 @@
+use crate::opcode::{
+    sign_ext_imm11, sign_ext_imm5, sign_ext_imm6, sign_ext_imm9, EncodeError, Opcode, Trap,
+};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AssembleError {
+    #[error("Line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("Line {line}: undefined label '{label}'")]
+    UndefinedLabel { line: usize, label: String },
+    #[error("Line {line}: duplicate label '{label}'")]
+    DuplicateLabel { line: usize, label: String },
+    #[error("Line {line}: {value} does not fit in {bits} bits")]
+    OutOfRange { line: usize, value: i32, bits: u32 },
+    #[error("Line {line}: {source}")]
+    Encode { line: usize, source: EncodeError },
+    #[error("Program is missing the .ORIG directive")]
+    MissingOrigin,
+    #[error("Program does not fit in memory")]
+    ProgramTooLarge,
+}
+
+/// An assembled program: the origin address, the words placed from it and the
+/// address of every label.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Program {
+    /// Words in the layout `Memory::load_program` expects: origin first.
+    pub fn to_words(&self) -> Vec<u16> {
+        std::iter::once(self.origin)
+            .chain(self.words.iter().copied())
+            .collect()
+    }
+
+    /// Bytes of the `.obj` file: every word big-endian, origin first.
+    pub fn to_obj_bytes(&self) -> Vec<u8> {
+        self.to_words()
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Register(u16),
+    Number(i32),
+    Label(String),
+    Text(String),
+}
+
+#[derive(Debug)]
+struct Statement {
+    line: usize,
+    address: u16,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+const TRAP_ALIASES: [(&str, u16); 6] = [
+    ("GETC", 0x20),
+    ("OUT", 0x21),
+    ("PUTS", 0x22),
+    ("IN", 0x23),
+    ("PUTSP", 0x24),
+    ("HALT", 0x25),
+];
+
+/// Assembles LC-3 source in two passes: the first lays out every statement and
+/// collects labels, the second encodes instructions with labels resolved.
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    let (origin, statements, symbols) = first_pass(source)?;
+
+    let mut words = Vec::new();
+    for statement in &statements {
+        words.extend(encode_statement(statement, &symbols)?);
+    }
+
+    Ok(Program {
+        origin,
+        words,
+        symbols,
+    })
+}
+
+type Layout = (u16, Vec<Statement>, BTreeMap<String, u16>);
+
+fn first_pass(source: &str) -> Result<Layout, AssembleError> {
+    let mut origin = None;
+    let mut address: u16 = 0;
+    let mut statements = Vec::new();
+    let mut symbols = BTreeMap::new();
+
+    for (index, text) in source.lines().enumerate() {
+        let line = index.saturating_add(1);
+        let mut tokens = tokenize(text, line)?;
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let first = tokens.remove(0);
+        let (label, mnemonic) = if is_mnemonic(&first) {
+            (None, first)
+        } else if tokens.is_empty() {
+            (Some(first), String::new())
+        } else {
+            (Some(first), tokens.remove(0))
+        };
+        let mnemonic = mnemonic.to_uppercase();
+
+        if mnemonic == ".ORIG" {
+            if origin.is_some() {
+                return Err(syntax(line, "only one .ORIG is supported"));
+            }
+            let operands = parse_operands(&tokens, line)?;
+            let start = match operands.as_slice() {
+                [Operand::Number(value)] => to_word(*value, line, 16)?,
+                _ => return Err(syntax(line, ".ORIG expects an address")),
+            };
+            origin = Some(start);
+            address = start;
+            continue;
+        }
+        if mnemonic == ".END" {
+            break;
+        }
+
+        if origin.is_none() {
+            return Err(AssembleError::MissingOrigin);
+        }
+
+        if let Some(label) = label {
+            let label = label.trim_end_matches(':').to_string();
+            if !is_valid_label(&label) {
+                return Err(syntax(line, &format!("invalid label '{}'", label)));
+            }
+            if symbols.insert(label.clone(), address).is_some() {
+                return Err(AssembleError::DuplicateLabel { line, label });
+            }
+        }
+        if mnemonic.is_empty() {
+            continue;
+        }
+
+        let operands = parse_operands(&tokens, line)?;
+        let size = statement_size(&mnemonic, &operands, line)?;
+        statements.push(Statement {
+            line,
+            address,
+            mnemonic,
+            operands,
+        });
+        address = address
+            .checked_add(size)
+            .ok_or(AssembleError::ProgramTooLarge)?;
+    }
+
+    let origin = origin.ok_or(AssembleError::MissingOrigin)?;
+    Ok((origin, statements, symbols))
+}
+
+fn statement_size(mnemonic: &str, operands: &[Operand], line: usize) -> Result<u16, AssembleError> {
+    match mnemonic {
+        ".BLKW" => match operands {
+            [Operand::Number(count)] | [Operand::Number(count), _] => {
+                u16::try_from(*count).map_err(|_| syntax(line, "invalid .BLKW size"))
+            }
+            _ => Err(syntax(line, ".BLKW expects a size")),
+        },
+        ".STRINGZ" => match operands {
+            [Operand::Text(text)] => u16::try_from(text.len())
+                .ok()
+                .and_then(|len| len.checked_add(1))
+                .ok_or(AssembleError::ProgramTooLarge),
+            _ => Err(syntax(line, ".STRINGZ expects a string")),
+        },
+        _ => Ok(1),
+    }
+}
+
+fn encode_statement(
+    statement: &Statement,
+    symbols: &BTreeMap<String, u16>,
+) -> Result<Vec<u16>, AssembleError> {
+    let line = statement.line;
+    let operands = statement.operands.as_slice();
+
+    let opcode = match statement.mnemonic.as_str() {
+        ".FILL" => {
+            let word = match operands {
+                [Operand::Number(value)] => to_word(*value, line, 16)?,
+                [Operand::Label(label)] => resolve(label, symbols, line)?,
+                _ => return Err(syntax(line, ".FILL expects a value or label")),
+            };
+            return Ok(vec![word]);
+        }
+        ".BLKW" => {
+            let fill = match operands {
+                [_, Operand::Number(value)] => to_word(*value, line, 16)?,
+                [_, Operand::Label(label)] => resolve(label, symbols, line)?,
+                _ => 0,
+            };
+            let count = statement_size(".BLKW", operands, line)?;
+            return Ok(vec![fill; count.into()]);
+        }
+        ".STRINGZ" => {
+            let text = match operands {
+                [Operand::Text(text)] => text,
+                _ => return Err(syntax(line, ".STRINGZ expects a string")),
+            };
+            return Ok(text.bytes().map(u16::from).chain([0]).collect());
+        }
+        "ADD" | "AND" => {
+            let add = statement.mnemonic == "ADD";
+            match (operands, add) {
+                ([Operand::Register(dr), Operand::Register(sr1), Operand::Register(sr2)], true) => {
+                    Opcode::OP_ADD_REG {
+                        dr: *dr,
+                        sr1: *sr1,
+                        sr2: *sr2,
+                    }
+                }
+                (
+                    [Operand::Register(dr), Operand::Register(sr1), Operand::Register(sr2)],
+                    false,
+                ) => Opcode::OP_AND_REG {
+                    dr: *dr,
+                    sr1: *sr1,
+                    sr2: *sr2,
+                },
+                ([Operand::Register(dr), Operand::Register(sr1), Operand::Number(imm)], _) => {
+                    let imm5 = signed_value(*imm, 5, line)?;
+                    if add {
+                        Opcode::OP_ADD_IMM {
+                            dr: *dr,
+                            sr1: *sr1,
+                            imm5,
+                        }
+                    } else {
+                        Opcode::OP_AND_IMM {
+                            dr: *dr,
+                            sr1: *sr1,
+                            imm5,
+                        }
+                    }
+                }
+                _ => return Err(syntax(line, "expected DR, SR1, SR2 or DR, SR1, imm5")),
+            }
+        }
+        "NOT" => match operands {
+            [Operand::Register(dr), Operand::Register(sr)] => Opcode::OP_NOT { dr: *dr, sr: *sr },
+            _ => return Err(syntax(line, "expected DR, SR")),
+        },
+        "JMP" => match operands {
+            [Operand::Register(base_r)] => Opcode::OP_JMP { base_r: *base_r },
+            _ => return Err(syntax(line, "expected a base register")),
+        },
+        "JSRR" => match operands {
+            [Operand::Register(base_r)] => Opcode::OP_JSRR { base_r: *base_r },
+            _ => return Err(syntax(line, "expected a base register")),
+        },
+        "RET" => {
+            expect_no_operands(operands, line)?;
+            Opcode::OP_RET
+        }
+        "RTI" => {
+            expect_no_operands(operands, line)?;
+            Opcode::OP_RTI
+        }
+        "JSR" => match operands {
+            [target] => Opcode::OP_JSR {
+                offset: pc_offset(target, statement.address, 11, symbols, line)?,
+            },
+            _ => return Err(syntax(line, "expected a label or offset")),
+        },
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => match operands {
+            [Operand::Register(register), target] => {
+                let register = *register;
+                let offset = pc_offset(target, statement.address, 9, symbols, line)?;
+                match statement.mnemonic.as_str() {
+                    "LD" => Opcode::OP_LD {
+                        dr: register,
+                        offset,
+                    },
+                    "LDI" => Opcode::OP_LDI {
+                        dr: register,
+                        offset,
+                    },
+                    "LEA" => Opcode::OP_LEA {
+                        dr: register,
+                        offset,
+                    },
+                    "ST" => Opcode::OP_ST {
+                        sr: register,
+                        offset,
+                    },
+                    _ => Opcode::OP_STI {
+                        sr: register,
+                        offset,
+                    },
+                }
+            }
+            _ => return Err(syntax(line, "expected a register and a label or offset")),
+        },
+        "LDR" | "STR" => match operands {
+            [Operand::Register(register), Operand::Register(base_r), Operand::Number(offset)] => {
+                let offset = signed_value(*offset, 6, line)?;
+                if statement.mnemonic == "LDR" {
+                    Opcode::OP_LDR {
+                        dr: *register,
+                        base_r: *base_r,
+                        offset,
+                    }
+                } else {
+                    Opcode::OP_STR {
+                        sr: *register,
+                        base_r: *base_r,
+                        offset,
+                    }
+                }
+            }
+            _ => {
+                return Err(syntax(
+                    line,
+                    "expected a register, a base register and offset6",
+                ))
+            }
+        },
+        "TRAP" => match operands {
+            [Operand::Number(vector)] => {
+                let trapvect8 = u16::try_from(*vector)
+                    .ok()
+                    .filter(|vector| *vector <= 0xFF)
+                    .ok_or(AssembleError::OutOfRange {
+                        line,
+                        value: *vector,
+                        bits: 8,
+                    })?;
+                Opcode::OP_TRAP {
+                    trapvec: Trap::from_vector(trapvect8).unwrap_or(Trap::Unknown(trapvect8)),
+                }
+            }
+            _ => return Err(syntax(line, "expected a trap vector")),
+        },
+        mnemonic => {
+            if let Some(trapvec) = trap_alias(mnemonic).and_then(Trap::from_vector) {
+                expect_no_operands(operands, line)?;
+                Opcode::OP_TRAP { trapvec }
+            } else if let Some((n, z, p)) = branch_flags(mnemonic) {
+                match operands {
+                    [target] => Opcode::OP_BR {
+                        n,
+                        z,
+                        p,
+                        offset: pc_offset(target, statement.address, 9, symbols, line)?,
+                    },
+                    _ => return Err(syntax(line, "expected a label or offset")),
+                }
+            } else {
+                return Err(syntax(line, &format!("unknown instruction '{}'", mnemonic)));
+            }
+        }
+    };
+
+    let word = opcode
+        .encode()
+        .map_err(|source| AssembleError::Encode { line, source })?;
+    Ok(vec![word])
+}
+
+// Sign-extended offset from the incremented PC to the target.
+fn pc_offset(
+    target: &Operand,
+    address: u16,
+    bits: u32,
+    symbols: &BTreeMap<String, u16>,
+    line: usize,
+) -> Result<u16, AssembleError> {
+    match target {
+        Operand::Number(offset) => signed_value(*offset, bits, line),
+        Operand::Label(label) => {
+            let target = resolve(label, symbols, line)?;
+            let offset = target.wrapping_sub(address.wrapping_add(1));
+            let value = i32::from(i16::from_ne_bytes(offset.to_ne_bytes()));
+            signed_value(value, bits, line)
+        }
+        _ => Err(syntax(line, "expected a label or offset")),
+    }
+}
+
+// Sign-extended 16-bit form of `value`, rejecting values that do not fit in `bits`.
+fn signed_value(value: i32, bits: u32, line: usize) -> Result<u16, AssembleError> {
+    let word = to_word(value, line, bits)?;
+    let sign_extended = match bits {
+        5 => sign_ext_imm5(word),
+        6 => sign_ext_imm6(word),
+        9 => sign_ext_imm9(word),
+        11 => sign_ext_imm11(word),
+        _ => word,
+    };
+    if i32::from(i16::from_ne_bytes(sign_extended.to_ne_bytes())) != value {
+        return Err(AssembleError::OutOfRange { line, value, bits });
+    }
+    Ok(word)
+}
+
+// Accepts both signed and unsigned 16-bit values.
+fn to_word(value: i32, line: usize, bits: u32) -> Result<u16, AssembleError> {
+    u16::try_from(value)
+        .or_else(|_| i16::try_from(value).map(|value| u16::from_ne_bytes(value.to_ne_bytes())))
+        .map_err(|_| AssembleError::OutOfRange { line, value, bits })
+}
+
+fn resolve(
+    label: &str,
+    symbols: &BTreeMap<String, u16>,
+    line: usize,
+) -> Result<u16, AssembleError> {
+    symbols
+        .get(label)
+        .copied()
+        .ok_or(AssembleError::UndefinedLabel {
+            line,
+            label: label.to_string(),
+        })
+}
+
+fn expect_no_operands(operands: &[Operand], line: usize) -> Result<(), AssembleError> {
+    if operands.is_empty() {
+        Ok(())
+    } else {
+        Err(syntax(line, "unexpected operands"))
+    }
+}
+
+fn syntax(line: usize, message: &str) -> AssembleError {
+    AssembleError::Syntax {
+        line,
+        message: message.to_string(),
+    }
+}
+
+fn trap_alias(mnemonic: &str) -> Option<u16> {
+    TRAP_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == mnemonic)
+        .map(|(_, vector)| *vector)
+}
+
+// BR without condition codes branches unconditionally, like BRnzp.
+fn branch_flags(mnemonic: &str) -> Option<(bool, bool, bool)> {
+    let flags = mnemonic.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some((true, true, true));
+    }
+    match flags {
+        "N" => Some((true, false, false)),
+        "Z" => Some((false, true, false)),
+        "P" => Some((false, false, true)),
+        "NZ" => Some((true, true, false)),
+        "NP" => Some((true, false, true)),
+        "ZP" => Some((false, true, true)),
+        "NZP" => Some((true, true, true)),
+        _ => None,
+    }
+}
+
+fn is_mnemonic(token: &str) -> bool {
+    let upper = token.to_uppercase();
+    matches!(
+        upper.as_str(),
+        "ADD"
+            | "AND"
+            | "NOT"
+            | "JMP"
+            | "JSR"
+            | "JSRR"
+            | "RET"
+            | "RTI"
+            | "LD"
+            | "LDI"
+            | "LDR"
+            | "LEA"
+            | "ST"
+            | "STI"
+            | "STR"
+            | "TRAP"
+            | ".ORIG"
+            | ".FILL"
+            | ".BLKW"
+            | ".STRINGZ"
+            | ".END"
+    ) || trap_alias(&upper).is_some()
+        || branch_flags(&upper).is_some()
+}
+
+fn is_valid_label(label: &str) -> bool {
+    let mut chars = label.chars();
+    let starts_well = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    starts_well
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_register(label).is_none()
+}
+
+fn parse_operands(tokens: &[String], line: usize) -> Result<Vec<Operand>, AssembleError> {
+    tokens
+        .iter()
+        .map(|token| parse_operand(token, line))
+        .collect()
+}
+
+fn parse_operand(token: &str, line: usize) -> Result<Operand, AssembleError> {
+    if let Some(text) = token.strip_prefix('"') {
+        return Ok(Operand::Text(text.to_string()));
+    }
+    if let Some(register) = parse_register(token) {
+        return Ok(Operand::Register(register));
+    }
+    if let Some(number) = parse_number(token) {
+        return Ok(Operand::Number(number));
+    }
+    if is_valid_label(token) {
+        return Ok(Operand::Label(token.to_string()));
+    }
+    Err(syntax(line, &format!("invalid operand '{}'", token)))
+}
+
+fn parse_register(token: &str) -> Option<u16> {
+    match token.as_bytes() {
+        [b'R' | b'r', digit @ b'0'..=b'7'] => Some(u16::from(digit.wrapping_sub(b'0'))),
+        _ => None,
+    }
+}
+
+// Numbers are written as #decimal, xhex, 0xhex or plain decimal.
+fn parse_number(token: &str) -> Option<i32> {
+    let (radix, digits) = if let Some(digits) = token.strip_prefix('#') {
+        (10, digits)
+    } else if let Some(digits) = token
+        .strip_prefix("0x")
+        .or_else(|| token.strip_prefix("0X"))
+        .or_else(|| token.strip_prefix('x'))
+        .or_else(|| token.strip_prefix('X'))
+    {
+        (16, digits)
+    } else {
+        (10, token)
+    };
+
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, digits),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    if negative {
+        value.checked_neg()
+    } else {
+        Some(value)
+    }
+}
+
+// Splits a line into tokens, dropping commas and comments. String literals are
+// kept as a single token with a leading quote and escapes resolved.
+fn tokenize(text: &str, line: usize) -> Result<Vec<String>, AssembleError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            '"' => {
+                let mut literal = String::from('"');
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => literal.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('0') => '\0',
+                            Some('e') => '\x1b',
+                            Some(other) => other,
+                            None => return Err(syntax(line, "unterminated string")),
+                        }),
+                        other => literal.push(other),
+                    }
+                }
+                if !closed {
+                    return Err(syntax(line, "unterminated string"));
+                }
+                if !literal.is_ascii() {
+                    return Err(syntax(line, "strings must be ASCII"));
+                }
+                tokens.push(literal);
+            }
+            c if c.is_whitespace() || c == ',' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_hello_world() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, HELLO   ; load the string
+            PUTS
+            HALT
+    HELLO   .STRINGZ "Hi"
+            .END
+        "#;
+        let program = assemble(source).unwrap();
+        assert_eq!(program.origin, 0x3000);
+        assert_eq!(
+            program.words,
+            vec![0xE002, 0xF022, 0xF025, u16::from(b'H'), u16::from(b'i'), 0]
+        );
+        assert_eq!(program.symbols.get("HELLO"), Some(&0x3003));
+    }
+
+    #[test]
+    fn test_assemble_operate_instructions() {
+        let source = "
+            .ORIG x3000
+            ADD R1, R2, R3
+            ADD R1, R2, #-3
+            AND R0, R0, #0
+            NOT R4, R5
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.words, vec![0x1283, 0x12BD, 0x5020, 0x997F]);
+    }
+
+    #[test]
+    fn test_assemble_control_instructions() {
+        let source = "
+            .ORIG x3000
+    LOOP    BRnz LOOP
+            BR DONE
+            JSR LOOP
+            JSRR R2
+            JMP R3
+    DONE    RET
+            RTI
+            TRAP x25
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program.words,
+            vec![0x0DFF, 0x0E03, 0x4FFD, 0x4080, 0xC0C0, 0xC1C0, 0x8000, 0xF025]
+        );
+    }
+
+    #[test]
+    fn test_assemble_memory_instructions() {
+        let source = "
+            .ORIG x3000
+            LD R0, DATA
+            LDI R1, DATA
+            LDR R2, R3, #-1
+            ST R0, DATA
+            STI R1, DATA
+            STR R2, R3, #5
+    DATA    .FILL xBEEF
+            .FILL DATA
+            .BLKW 2 #7
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program.words,
+            vec![0x2005, 0xA204, 0x64FF, 0x3002, 0xB201, 0x74C5, 0xBEEF, 0x3006, 7, 7]
+        );
+    }
+
+    #[test]
+    fn test_obj_bytes() {
+        let program = assemble(".ORIG x3000\nHALT\n.END").unwrap();
+        assert_eq!(program.to_obj_bytes(), vec![0x30, 0x00, 0xF0, 0x25]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(assemble("HALT"), Err(AssembleError::MissingOrigin));
+        assert_eq!(
+            assemble(".ORIG x3000\nBR NOWHERE\n.END"),
+            Err(AssembleError::UndefinedLabel {
+                line: 2,
+                label: "NOWHERE".to_string()
+            })
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nA HALT\nA HALT\n.END"),
+            Err(AssembleError::DuplicateLabel {
+                line: 3,
+                label: "A".to_string()
+            })
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nADD R0, R0, #16\n.END"),
+            Err(AssembleError::OutOfRange {
+                line: 2,
+                value: 16,
+                bits: 5
+            })
+        );
+        assert!(matches!(
+            assemble(".ORIG x3000\nFOO R0\n.END"),
+            Err(AssembleError::Syntax { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// Character I/O used by the trap routines and the keyboard registers.
+pub trait Console {
+    /// Blocks until a byte of input is available.
+    fn read_byte(&mut self) -> io::Result<u8>;
+    /// Returns a byte of input if one is available.
+    fn poll_byte(&mut self) -> io::Result<Option<u8>>;
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// Called before every instruction with the number executed so far, so
+    /// input can be tied to a point in the execution.
+    fn set_instruction_count(&mut self, _count: u64) {}
+}
+
+impl<C: Console + ?Sized> Console for Box<C> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        (**self).read_byte()
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        (**self).poll_byte()
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        (**self).write_bytes(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn set_instruction_count(&mut self, count: u64) {
+        (**self).set_instruction_count(count);
+    }
+}
+
+/// Console backed by the process stdin and stdout.
+///
+/// Stdin is read on a background thread so that `poll_byte` never blocks. The
+/// thread only reads a byte after the VM asks for one, so input the program has
+/// not asked for yet stays in stdin.
+#[derive(Default)]
+pub struct StdioConsole {
+    reader: Option<StdinReader>,
+    eof: bool,
+}
+
+struct StdinReader {
+    requests: Sender<()>,
+    bytes: Receiver<io::Result<u8>>,
+    pending: bool,
+}
+
+impl StdinReader {
+    fn spawn() -> Self {
+        let (requests, request_rx) = mpsc::channel::<()>();
+        let (byte_tx, bytes) = mpsc::channel();
+        thread::spawn(move || {
+            while request_rx.recv().is_ok() {
+                let mut buffer = [0; 1];
+                let result = io::stdin().read_exact(&mut buffer).map(|_| {
+                    let [byte] = buffer;
+                    byte
+                });
+                if byte_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            requests,
+            bytes,
+            pending: false,
+        }
+    }
+
+    fn request(&mut self) -> io::Result<()> {
+        if !self.pending {
+            self.requests
+                .send(())
+                .map_err(|_| io::Error::other("Stdin reader stopped"))?;
+            self.pending = true;
+        }
+        Ok(())
+    }
+}
+
+impl StdioConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn reader(&mut self) -> &mut StdinReader {
+        self.reader.get_or_insert_with(StdinReader::spawn)
+    }
+
+    fn received(&mut self, result: io::Result<u8>) -> io::Result<u8> {
+        if let Err(err) = &result {
+            self.eof = err.kind() == io::ErrorKind::UnexpectedEof;
+        }
+        result
+    }
+}
+
+impl Console for StdioConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if self.eof {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let reader = self.reader();
+        reader.request()?;
+        let result = reader
+            .bytes
+            .recv()
+            .map_err(|_| io::Error::other("Stdin reader stopped"))?;
+        reader.pending = false;
+        self.received(result)
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.eof {
+            return Ok(None);
+        }
+
+        let reader = self.reader();
+        reader.request()?;
+        match reader.bytes.try_recv() {
+            Ok(result) => {
+                reader.pending = false;
+                match self.received(result) {
+                    Ok(byte) => Ok(Some(byte)),
+                    Err(_) if self.eof => Ok(None),
+                    Err(err) => Err(err),
+                }
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(io::Error::other("Stdin reader stopped")),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+/// In-memory console. Clones share the same buffers, so a handle kept outside
+/// the VM can feed input and inspect the output of a running program.
+#[derive(Clone, Default)]
+pub struct BufferedConsole {
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+impl BufferedConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_input(input: impl AsRef<[u8]>) -> Self {
+        let console = Self::new();
+        console.push_input(input);
+        console
+    }
+
+    pub fn push_input(&self, input: impl AsRef<[u8]>) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            buffers.input.extend(input.as_ref());
+        }
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .map(|buffers| buffers.output.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output()).into_owned()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .map(|mut buffers| std::mem::take(&mut buffers.output))
+            .unwrap_or_default()
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Buffers>> {
+        self.buffers
+            .lock()
+            .map_err(|_| io::Error::other("Console buffers poisoned"))
+    }
+}
+
+impl Console for BufferedConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.lock()?.input.pop_front().ok_or(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Input exhausted",
+        ))
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.lock()?.input.pop_front())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.lock()?.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffered_console_input() {
+        let mut console = BufferedConsole::with_input("ab");
+        assert_eq!(console.poll_byte().unwrap(), Some(b'a'));
+        assert_eq!(console.read_byte().unwrap(), b'b');
+        assert_eq!(console.poll_byte().unwrap(), None);
+        assert!(console.read_byte().is_err());
+    }
+
+    #[test]
+    fn test_buffered_console_shared_output() {
+        let handle = BufferedConsole::new();
+        let mut console = handle.clone();
+        console.write_bytes(b"Hello").unwrap();
+        assert_eq!(handle.output_string(), "Hello");
+        assert_eq!(handle.take_output(), b"Hello");
+        assert!(handle.output().is_empty());
+    }
+}
@@ -1,7 +1,14 @@
-use crate::flags::ConditionFlags;
-use crate::memory::Memory;
+use crate::console::{Console, StdioConsole};
+use crate::flags::{ConditionFlags, PSR_COND, PSR_PRIORITY, PSR_USER_MODE};
+use crate::interrupt::InterruptRequest;
+use crate::memory::{Memory, MemoryError};
 use crate::opcode::{Opcode, Trap};
-use std::io::{self, Read, Write};
+use crate::snapshot::{Snapshot, SnapshotError};
+use crate::step::{AccessLog, MemoryRead, MemoryWrite, RegisterWrite, StepOutcome};
+use crate::trace::Tracer;
+use crate::undo::{UndoLog, UndoRecord};
+use std::io;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -12,6 +19,54 @@ pub enum CPUError {
     Execute(String),
     #[error("Fail decoding instruction")]
     Decode(String),
+    #[error("Privilege mode violation at x{pc:04X} (instruction x{instruction:04X})")]
+    PrivilegeViolation { pc: u16, instruction: u16 },
+    #[error("Illegal opcode at x{pc:04X} (instruction x{instruction:04X})")]
+    IllegalOpcode { pc: u16, instruction: u16 },
+    #[error("Access control violation at x{pc:04X} (instruction x{instruction:04X}) accessing x{address:04X}")]
+    AccessViolation {
+        pc: u16,
+        instruction: u16,
+        address: u16,
+    },
+    #[error("Unknown trap vector at x{pc:04X} (instruction x{instruction:04X})")]
+    UnknownTrap { pc: u16, instruction: u16 },
+}
+
+impl CPUError {
+    /// The interrupt vector hardware would raise for this error, if any.
+    pub fn exception_vector(&self) -> Option<u16> {
+        match self {
+            CPUError::PrivilegeViolation { .. } => Some(PRIVILEGE_MODE_VECTOR),
+            CPUError::IllegalOpcode { .. } => Some(ILLEGAL_OPCODE_VECTOR),
+            CPUError::AccessViolation { .. } => Some(ACCESS_CONTROL_VECTOR),
+            _ => None,
+        }
+    }
+}
+
+/// How TRAP instructions are serviced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrapMode {
+    /// Built-in Rust implementations of the standard service routines.
+    #[default]
+    Native,
+    /// Jump through the trap vector table at x0000-x00FF, so programs and OS
+    /// images can install their own routines.
+    VectorTable,
+    /// Jump through the trap vector table, entering every routine like an
+    /// exception from either mode so that all of them return with RTI.
+    Exception,
+}
+
+/// How exceptions raised by an instruction are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExceptionMode {
+    /// Stop and report the exception as a `CPUError`.
+    #[default]
+    Lenient,
+    /// Dispatch through the interrupt vector table like the hardware does.
+    Strict,
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -25,13 +80,57 @@ pub struct CPU {
     pub r6: u16,
     pub r7: u16,
     pub pc: u16,
-    pub cond: u16,
+    /// Instruction register: the word currently being executed.
+    pub ir: u16,
+    /// Processor Status Register: privilege, priority and condition codes.
+    pub psr: u16,
+    /// Stack pointers saved while the other privilege mode owns R6.
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
     pub memory: Memory,
     pub running: bool,
+    /// Instructions executed since the CPU was created.
+    pub instruction_count: u64,
+    /// Wall-clock limit applied to each call of the run loop. It is checked
+    /// between instructions, so a blocking console read is not interrupted.
+    pub timeout: Option<Duration>,
+    pub exception_mode: ExceptionMode,
+    pub trap_mode: TrapMode,
+    /// Receives every executed instruction when set.
+    pub tracer: Option<Tracer>,
+    /// Records what each instruction changed so it can be undone.
+    pub undo_log: Option<UndoLog>,
+    accesses: AccessLog,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    Halted,
+    BudgetExhausted,
+    TimedOut,
+}
+
+const DEADLINE_CHECK_MASK: u64 = 0x3FF;
+const SUPERVISOR_STACK: u16 = 0x3000;
+const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
+const USER_SPACE: u16 = 0x3000;
+const DEVICE_SPACE: u16 = 0xFE00;
+pub const PRIVILEGE_MODE_VECTOR: u16 = 0x00;
+pub const ILLEGAL_OPCODE_VECTOR: u16 = 0x01;
+pub const ACCESS_CONTROL_VECTOR: u16 = 0x02;
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
     pub fn new() -> Self {
+        Self::with_console(Box::new(StdioConsole::new()))
+    }
+
+    pub fn with_console(console: Box<dyn Console>) -> Self {
         Self {
             r0: 0,
             r1: 0,
@@ -39,26 +138,253 @@ impl CPU {
             r3: 0,
             r4: 0,
             r5: 0,
-            r6: 0,
+            // The machine resets into supervisor mode, where R6 is the
+            // supervisor stack pointer, so exceptions and interrupts taken
+            // before anything sets R6 still have a stack to push onto
+            r6: SUPERVISOR_STACK,
             r7: 0,
             pc: 0x3000,
-            cond: 0,
-            memory: Memory::new(),
+            ir: 0,
+            psr: ConditionFlags::ZRO.into(),
+            saved_ssp: SUPERVISOR_STACK,
+            saved_usp: 0,
+            memory: Memory::with_console(console),
             running: true,
+            instruction_count: 0,
+            timeout: None,
+            exception_mode: ExceptionMode::default(),
+            trap_mode: TrapMode::default(),
+            tracer: None,
+            undo_log: None,
+            accesses: AccessLog::default(),
+        }
+    }
+
+    /// Serializes the registers, memory and device state into a snapshot.
+    pub fn save_state(&self) -> Vec<u8> {
+        Snapshot::capture(self).to_bytes()
+    }
+
+    /// Restores a snapshot taken by `save_state`.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        Snapshot::from_bytes(bytes)?.restore(self)?;
+        if let Some(undo_log) = &mut self.undo_log {
+            undo_log.clear();
+        }
+        Ok(())
+    }
+
+    /// Undoes the last instruction recorded in the undo log, returning its
+    /// record, or `None` when there is nothing left to undo.
+    pub fn step_back(&mut self) -> Result<Option<UndoRecord>, MemoryError> {
+        let Some(record) = self.undo_log.as_mut().and_then(UndoLog::pop) else {
+            return Ok(None);
+        };
+        record.restore(self)?;
+        Ok(Some(record))
+    }
+
+    /// Runs until the program halts or the wall-clock `timeout` elapses.
+    pub fn execute_program(&mut self) -> Result<RunOutcome, CPUError> {
+        self.run_until(None)
+    }
+
+    /// Like `execute_program`, but also stops after `max_instructions`.
+    pub fn run_for(&mut self, max_instructions: u64) -> Result<RunOutcome, CPUError> {
+        self.run_until(Some(max_instructions))
+    }
+
+    fn run_until(&mut self, budget: Option<u64>) -> Result<RunOutcome, CPUError> {
+        let result = self.run_loop(budget);
+        // However the run stopped, the trace so far reaches its file
+        if let Some(tracer) = &mut self.tracer {
+            let flushed = tracer.flush();
+            if result.is_ok() {
+                flushed.map_err(|err| CPUError::Execute(format!("Writing trace: {}", err)))?;
+            }
         }
+        result
     }
 
-    pub fn execute_program(&mut self) -> Result<(), CPUError> {
+    fn run_loop(&mut self, budget: Option<u64>) -> Result<RunOutcome, CPUError> {
+        let deadline = self
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut executed: u64 = 0;
+
         while self.running {
-            let instruction = self
-                .fetch_instruction()
-                .ok_or(CPUError::Decode("Fetching instruction".to_string()))?;
-            self.pc = self.pc.wrapping_add(1);
-            let opcode =
-                Opcode::from(instruction).map_err(|err| CPUError::Decode(format!("{:?}", err)))?;
-            self.execute(opcode)?;
+            if budget.is_some_and(|budget| executed >= budget) {
+                return Ok(RunOutcome::BudgetExhausted);
+            }
+            // Reading the clock on every instruction would slow the loop down
+            if executed & DEADLINE_CHECK_MASK == 0
+                && deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Ok(RunOutcome::TimedOut);
+            }
+
+            self.step()?;
+            executed = executed.saturating_add(1);
+        }
+
+        Ok(RunOutcome::Halted)
+    }
+
+    /// Fetches, decodes and executes one instruction, reporting what it did.
+    pub fn step(&mut self) -> Result<StepOutcome, CPUError> {
+        let Some(mut record) = self.undo_log.as_ref().map(|_| UndoRecord::new(self)) else {
+            return self.execute_step();
+        };
+
+        self.memory.start_journal();
+        let result = self.execute_step();
+        record.memory = self.memory.take_journal();
+        // A failed instruction may have changed state before failing
+        record.register_writes = match &result {
+            Ok(outcome) => outcome.register_writes.clone(),
+            Err(_) => self.accesses.register_writes.clone(),
+        };
+        if let Some(undo_log) = &mut self.undo_log {
+            undo_log.push(record);
+        }
+        result
+    }
+
+    fn execute_step(&mut self) -> Result<StepOutcome, CPUError> {
+        let mut address = self.pc;
+        let result = self.execute_instruction(&mut address);
+        let cond = self.cond();
+        let Some(tracer) = &mut self.tracer else {
+            return result;
+        };
+
+        match &result {
+            Ok(outcome) => {
+                tracer
+                    .record(self.instruction_count, outcome, cond)
+                    .and_then(|_| {
+                        // Halting may be the last chance before the process exits
+                        if outcome.halted {
+                            tracer.flush()
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .map_err(|err| CPUError::Execute(format!("Writing trace: {}", err)))?;
+            }
+            // The instruction's own error is the one worth reporting
+            Err(err) => {
+                let instruction = self.memory.peek(address);
+                let _ = tracer
+                    .record_error(
+                        self.instruction_count.wrapping_add(1),
+                        address,
+                        instruction,
+                        err,
+                    )
+                    .and_then(|_| tracer.flush());
+            }
+        }
+        result
+    }
+
+    // `address` is kept up to date with the instruction being executed, for
+    // tracing it if it fails.
+    fn execute_instruction(&mut self, address: &mut u16) -> Result<StepOutcome, CPUError> {
+        let pc_before = self.pc;
+        self.accesses = AccessLog::default();
+        self.memory
+            .console_mut()
+            .set_instruction_count(self.instruction_count);
+        let interrupt = self.service_interrupts()?;
+
+        if let Err(err) = self.check_access(self.pc) {
+            // Faults before the fetch, so it reports the instruction at PC itself
+            let err = match err {
+                CPUError::AccessViolation { address, .. } => CPUError::AccessViolation {
+                    pc: address,
+                    instruction: self.memory.peek(address),
+                    address,
+                },
+                err => err,
+            };
+            self.raise(err)?;
+        }
+
+        *address = self.pc;
+        let address = self.pc;
+        let instruction = self
+            .fetch_instruction()
+            .ok_or(CPUError::Decode("Fetching instruction".to_string()))?;
+        self.ir = instruction;
+        self.pc = self.pc.wrapping_add(1);
+        let opcode =
+            Opcode::from(instruction).map_err(|err| CPUError::Decode(format!("{:?}", err)))?;
+
+        if let Err(err) = self.execute(opcode) {
+            self.raise(err)?;
+        }
+        self.memory.tick();
+        if !self.memory.clock_enabled() {
+            self.running = false;
+        }
+        self.instruction_count = self.instruction_count.wrapping_add(1);
+        let accesses = std::mem::take(&mut self.accesses);
+
+        let outcome = StepOutcome {
+            pc_before,
+            interrupt,
+            pc_after: self.pc,
+            address,
+            instruction,
+            opcode,
+            register_writes: accesses.register_writes,
+            memory_reads: accesses.memory_reads,
+            memory_writes: accesses.memory_writes,
+            trap: match opcode {
+                Opcode::OP_TRAP { trapvec } => Some(trapvec),
+                _ => None,
+            },
+            halted: !self.running,
+        };
+
+        Ok(outcome)
+    }
+
+    // Takes a pending device interrupt when its priority is above the current one.
+    fn service_interrupts(&mut self) -> Result<Option<InterruptRequest>, CPUError> {
+        let Some(request) = self.memory.interrupt_request() else {
+            return Ok(None);
+        };
+        if request.priority <= self.priority() {
+            return Ok(None);
+        }
+
+        self.enter_interrupt(request.vector, request.priority)?;
+        Ok(Some(request))
+    }
+
+    // Strict mode takes exceptions through the vector table; anything else is returned.
+    fn raise(&mut self, err: CPUError) -> Result<(), CPUError> {
+        match err.exception_vector() {
+            Some(vector) if self.exception_mode == ExceptionMode::Strict => {
+                self.enter_exception(vector)
+            }
+            _ => Err(err),
         }
+    }
 
+    /// User mode may not touch system space or the device registers. Every
+    /// fetch and every load or store instruction is checked here.
+    fn check_access(&self, address: u16) -> Result<(), CPUError> {
+        let protected = !(USER_SPACE..DEVICE_SPACE).contains(&address);
+        if self.is_user_mode() && protected {
+            return Err(CPUError::AccessViolation {
+                pc: self.pc.wrapping_sub(1),
+                instruction: self.ir,
+                address,
+            });
+        }
         Ok(())
     }
 
@@ -66,6 +392,26 @@ impl CPU {
         self.memory.read(self.pc.into())
     }
 
+    // Data accesses made by instructions go through these two so they are logged.
+    fn read_memory(&mut self, address: u16) -> Option<u16> {
+        let value = self.memory.read(address.into())?;
+        self.accesses
+            .memory_reads
+            .push(MemoryRead { address, value });
+        Some(value)
+    }
+
+    fn write_memory(&mut self, address: u16, value: u16) -> Result<(), MemoryError> {
+        let old = self.memory.peek(address);
+        self.memory.write(address, value)?;
+        self.accesses.memory_writes.push(MemoryWrite {
+            address,
+            old,
+            new: value,
+        });
+        Ok(())
+    }
+
     pub fn execute(&mut self, opcode: Opcode) -> Result<(), CPUError> {
         match opcode {
             Opcode::OP_ADD_REG { dr, sr1, sr2 } => {
@@ -116,9 +462,10 @@ impl CPU {
             Opcode::OP_BR { n, z, p, offset } => {
                 // If any of the condition codes tested is set, the program branches to the location
                 // specified by adding the sign-extended PCoffset9 field to the incremented PC.
-                if (n && self.cond == ConditionFlags::NEG.into())
-                    || (z && self.cond == ConditionFlags::ZRO.into())
-                    || (p && self.cond == ConditionFlags::POS.into())
+                let cond = self.cond();
+                if (n && cond & u16::from(ConditionFlags::NEG) != 0)
+                    || (z && cond & u16::from(ConditionFlags::ZRO) != 0)
+                    || (p && cond & u16::from(ConditionFlags::POS) != 0)
                 {
                     self.pc = self.pc.wrapping_add(offset);
                 }
@@ -129,18 +476,19 @@ impl CPU {
                     .map_err(|err| CPUError::Execute(format!("JMP: {}", err)))?;
             }
             Opcode::OP_JSR { offset } => {
-                self.r7 = self.pc;
+                self.update_register(7, self.pc)?;
                 self.pc = self.pc.wrapping_add(offset);
             }
             Opcode::OP_JSRR { base_r } => {
-                self.r7 = self.pc;
+                self.update_register(7, self.pc)?;
                 self.pc = self
                     .get_register_value(base_r)
                     .map_err(|err| CPUError::Execute(format!("JSRR: {}", err)))?;
             }
             Opcode::OP_LD { dr, offset } => {
                 let address = self.pc.wrapping_add(offset);
-                if let Some(read_value) = self.memory.read(address.into()) {
+                self.check_access(address)?;
+                if let Some(read_value) = self.read_memory(address) {
                     self.update_register(dr, read_value)
                         .map_err(|err| CPUError::Execute(format!("LD: {}", err)))?;
                     self.update_flag(dr)
@@ -149,13 +497,13 @@ impl CPU {
             }
             Opcode::OP_LDI { dr, offset } => {
                 let address = self.pc.wrapping_add(offset);
+                self.check_access(address)?;
                 let first_read = self
-                    .memory
-                    .read(address.into())
+                    .read_memory(address)
                     .ok_or(CPUError::Execute("LDI".to_string()))?;
+                self.check_access(first_read)?;
                 let read_value = self
-                    .memory
-                    .read(first_read.into())
+                    .read_memory(first_read)
                     .ok_or(CPUError::Execute("LDI".to_string()))?;
 
                 self.update_register(dr, read_value)
@@ -166,9 +514,9 @@ impl CPU {
             Opcode::OP_LDR { dr, base_r, offset } => {
                 let base_value = self.get_register_value(base_r)?;
                 let address = base_value.wrapping_add(offset);
+                self.check_access(address)?;
                 let read_value = self
-                    .memory
-                    .read(address.into())
+                    .read_memory(address)
                     .ok_or(CPUError::Execute("LDR".to_string()))?;
                 self.update_register(dr, read_value)?;
                 self.update_flag(dr)?;
@@ -187,53 +535,89 @@ impl CPU {
                 self.pc = self.r7;
             }
             Opcode::OP_RTI => {
-                println!("unused RTI")
+                if self.is_user_mode() {
+                    return Err(CPUError::PrivilegeViolation {
+                        pc: self.pc.wrapping_sub(1),
+                        instruction: self.ir,
+                    });
+                }
+
+                self.pc = self.pop().ok_or(CPUError::Execute("RTI".to_string()))?;
+                let psr = self.pop().ok_or(CPUError::Execute("RTI".to_string()))?;
+                self.psr = psr;
+                if self.is_user_mode() {
+                    self.saved_ssp = self.r6;
+                    self.update_register(6, self.saved_usp)?;
+                }
             }
             Opcode::OP_RES => {
-                println!("unused RES");
+                return Err(CPUError::IllegalOpcode {
+                    pc: self.pc.wrapping_sub(1),
+                    instruction: self.ir,
+                });
             }
             Opcode::OP_ST { sr, offset } => {
                 let address = self.pc.wrapping_add(offset);
+                self.check_access(address)?;
                 let sr_register = self.get_register_value(sr)?;
 
-                self.memory
-                    .write(address, sr_register)
+                self.write_memory(address, sr_register)
                     .map_err(|err| CPUError::Execute(format!("ST: {}", err)))?;
             }
             Opcode::OP_STI { sr, offset } => {
                 let address = self.pc.wrapping_add(offset);
+                self.check_access(address)?;
                 let read_address = self
-                    .memory
-                    .read(address.into())
+                    .read_memory(address)
                     .ok_or(CPUError::Execute("STI".to_string()))?;
+                self.check_access(read_address)?;
 
                 let sr_register = self.get_register_value(sr)?;
 
-                self.memory
-                    .write(read_address, sr_register)
+                self.write_memory(read_address, sr_register)
                     .map_err(|err| CPUError::Execute(format!("STI: {}", err)))?;
             }
             Opcode::OP_STR { sr, base_r, offset } => {
                 let base_value = self.get_register_value(base_r)?;
                 let address = base_value.wrapping_add(offset);
+                self.check_access(address)?;
                 let sr_value = self.get_register_value(sr)?;
-                self.memory
-                    .write(address, sr_value)
+                self.write_memory(address, sr_value)
                     .map_err(|err| CPUError::Execute(format!("STR: {}", err)))?;
             }
             Opcode::OP_TRAP { trapvec } => {
-                self.r7 = self.pc;
+                let from_table =
+                    self.trap_mode != TrapMode::Native || matches!(trapvec, Trap::Unknown(_));
+                if let Trap::Unknown(_) = trapvec {
+                    if self.trap_mode == TrapMode::Native
+                        && self.exception_mode == ExceptionMode::Lenient
+                    {
+                        return Err(CPUError::UnknownTrap {
+                            pc: self.pc.wrapping_sub(1),
+                            instruction: self.ir,
+                        });
+                    }
+                }
+                self.update_register(7, self.pc)?;
+                if from_table {
+                    // From user mode the routine runs privileged, like an exception
+                    if self.trap_mode == TrapMode::Exception || self.is_user_mode() {
+                        return self.enter_service_routine(trapvec.vector(), None);
+                    }
+                    self.pc = self
+                        .read_memory(trapvec.vector())
+                        .ok_or(CPUError::Execute("Reading trap vector".to_string()))?;
+                    return Ok(());
+                }
+
                 match trapvec {
                     Trap::GetC => {
-                        let mut buffer: [u8; 1] = [0; 1];
-                        io::stdin()
-                            .read_exact(&mut buffer)
+                        let read_char = self
+                            .memory
+                            .read_key()
                             .map_err(|err| CPUError::Execute(format!("GetC: {}", err)))?;
-                        let read_char = buffer
-                            .first()
-                            .ok_or(CPUError::Execute("GetC".to_string()))?;
 
-                        self.r0 = (*read_char).into();
+                        self.update_register(0, read_char.into())?;
                         self.update_flag(0)?;
                     }
                     Trap::Out => {
@@ -241,96 +625,84 @@ impl CPU {
                             .r0
                             .try_into()
                             .map_err(|err| CPUError::Execute(format!("Out: {}", err)))?;
-                        let char: char = r0_value.into();
-                        print!("{}", char);
-                        io::stdout()
-                            .flush()
+                        self.print(&[r0_value])
                             .map_err(|err| CPUError::Execute(format!("Out: {err}")))?;
                     }
                     Trap::Puts => {
                         let mut address = self.r0;
                         let mut value = self
-                            .memory
-                            .read(address.into())
+                            .read_memory(address)
                             .ok_or(CPUError::Execute("Puts".to_string()))?;
 
+                        let mut chars = Vec::new();
                         while value != 0x0000 {
                             let c: u8 = value
                                 .try_into()
                                 .map_err(|err| CPUError::Execute(format!("Out: {err}")))?;
-                            let c: char = c.into();
-                            print!("{}", c);
+                            chars.push(c);
                             address = address.wrapping_add(1);
                             value = self
-                                .memory
-                                .read(address.into())
+                                .read_memory(address)
                                 .ok_or(CPUError::Execute("Puts".to_string()))?;
                         }
 
-                        io::stdout()
-                            .flush()
+                        self.print(&chars)
                             .map_err(|err| CPUError::Execute(format!("Out: {err}")))?;
                     }
                     Trap::In => {
-                        print!("Enter a character: ");
-                        io::stdout()
-                            .flush()
+                        self.print(b"Enter a character: ")
                             .map_err(|err| CPUError::Execute(format!("In: {err}")))?;
 
-                        let mut buffer: [u8; 1] = [0; 1];
-                        io::stdin()
-                            .read_exact(&mut buffer)
+                        let read_char = self
+                            .memory
+                            .read_key()
+                            .map_err(|err| CPUError::Execute(format!("In: {err}")))?;
+                        self.print(&[read_char])
                             .map_err(|err| CPUError::Execute(format!("In: {err}")))?;
-                        let read_char =
-                            buffer.first().ok_or(CPUError::Execute("In".to_string()))?;
-                        let char: char = (*read_char).into();
-                        print!("{}", char);
 
-                        self.update_register(0, (*read_char).into())?;
+                        self.update_register(0, read_char.into())?;
                         self.update_flag(0)?;
-                        io::stdout()
-                            .flush()
-                            .map_err(|err| CPUError::Execute(format!("In: {err}")))?;
                     }
                     Trap::Putsp => {
                         let mut address = self.r0;
                         let mut value = self
-                            .memory
-                            .read(address.into())
+                            .read_memory(address)
                             .ok_or(CPUError::Execute("Putsp".to_string()))?;
 
+                        let mut chars = Vec::new();
                         while value != 0x0000 {
-                            let first_char = (value >> 8) & 0b0000_0000_1111_1111;
-                            let second_char = value & 0b0000_0000_1111_1111;
+                            let first_char = value & 0b0000_0000_1111_1111;
+                            let second_char = (value >> 8) & 0b0000_0000_1111_1111;
 
                             let first_c: u8 = first_char
                                 .try_into()
                                 .map_err(|err| CPUError::Execute(format!("Putsp: {err}")))?;
-                            let first_c: char = first_c.into();
-                            print!("{}", first_c);
+                            chars.push(first_c);
 
                             let second_c: u8 = second_char
                                 .try_into()
                                 .map_err(|err| CPUError::Execute(format!("Putsp: {err}")))?;
                             if second_c != 0x00 {
-                                let second_c: char = second_c.into();
-                                print!("{}", second_c);
+                                chars.push(second_c);
                             }
 
                             address = address.wrapping_add(1);
                             value = self
-                                .memory
-                                .read(address.into())
+                                .read_memory(address)
                                 .ok_or(CPUError::Execute("Putsp".to_string()))?;
                         }
 
-                        io::stdout()
-                            .flush()
+                        self.print(&chars)
                             .map_err(|err| CPUError::Execute(format!("Putsp: {err}")))?;
                     }
                     Trap::Halt => {
+                        self.memory
+                            .stop_clock()
+                            .map_err(|err| CPUError::Execute(format!("Halt: {}", err)))?;
                         self.running = false;
                     }
+                    // Dispatched through the trap vector table above
+                    Trap::Unknown(_) => {}
                 };
             }
         };
@@ -338,6 +710,14 @@ impl CPU {
         Ok(())
     }
 
+    // Every byte is written as the char with that code point, as `print!` would.
+    fn print(&mut self, chars: &[u8]) -> io::Result<()> {
+        let text: String = chars.iter().copied().map(char::from).collect();
+        let console = self.memory.console_mut();
+        console.write_bytes(text.as_bytes())?;
+        console.flush()
+    }
+
     pub fn get_register(&mut self, index: u16) -> Result<&mut u16, CPUError> {
         let register_value = match index {
             0 => &mut self.r0,
@@ -384,29 +764,99 @@ impl CPU {
 
     pub fn update_register(&mut self, index: u16, value: u16) -> Result<(), CPUError> {
         let register = self.get_register(index)?;
+        let old = *register;
         *register = value;
+        self.accesses.register_writes.push(RegisterWrite {
+            index,
+            old,
+            new: value,
+        });
         Ok(())
     }
 
     pub fn update_flag(&mut self, register: u16) -> Result<(), CPUError> {
         let register_value = self.get_register(register)?;
 
-        if *register_value == 0 {
-            self.cond = ConditionFlags::ZRO.into();
+        let flag = if *register_value == 0 {
+            ConditionFlags::ZRO
         } else if (*register_value >> 15) == 1 {
-            self.cond = ConditionFlags::NEG.into();
+            ConditionFlags::NEG
         } else {
-            self.cond = ConditionFlags::POS.into();
+            ConditionFlags::POS
+        };
+        self.set_cond(flag);
+
+        Ok(())
+    }
+
+    pub fn cond(&self) -> u16 {
+        self.psr & PSR_COND
+    }
+
+    pub fn set_cond(&mut self, flag: ConditionFlags) {
+        self.psr = (self.psr & !PSR_COND) | u16::from(flag);
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.psr & PSR_USER_MODE != 0
+    }
+
+    pub fn priority(&self) -> u16 {
+        (self.psr & PSR_PRIORITY) >> 8
+    }
+
+    /// Enters an exception handler: switches to the supervisor stack, pushes
+    /// the PSR and PC and jumps through the interrupt vector table.
+    pub fn enter_exception(&mut self, vector: u16) -> Result<(), CPUError> {
+        self.enter_service_routine(INTERRUPT_VECTOR_TABLE | (vector & 0xFF), None)
+    }
+
+    /// Like `enter_exception`, but also raises the priority level to `priority`.
+    pub fn enter_interrupt(&mut self, vector: u16, priority: u16) -> Result<(), CPUError> {
+        self.enter_service_routine(INTERRUPT_VECTOR_TABLE | (vector & 0xFF), Some(priority))
+    }
+
+    // `entry` is the address of the vector table entry holding the routine.
+    fn enter_service_routine(&mut self, entry: u16, priority: Option<u16>) -> Result<(), CPUError> {
+        let psr = self.psr;
+        if self.is_user_mode() {
+            self.saved_usp = self.r6;
+            self.update_register(6, self.saved_ssp)?;
         }
 
+        self.psr &= !PSR_USER_MODE;
+        if let Some(priority) = priority {
+            self.psr = (self.psr & !PSR_PRIORITY) | ((priority << 8) & PSR_PRIORITY);
+        }
+
+        self.push(psr)?;
+        self.push(self.pc)?;
+        self.pc = self
+            .read_memory(entry)
+            .ok_or(CPUError::Execute("Reading interrupt vector".to_string()))?;
+
         Ok(())
     }
+
+    fn push(&mut self, value: u16) -> Result<(), CPUError> {
+        let sp = self.r6.wrapping_sub(1);
+        self.update_register(6, sp)?;
+        self.write_memory(sp, value)
+            .map_err(|err| CPUError::Execute(format!("Pushing to the stack: {}", err)))
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        let value = self.read_memory(self.r6)?;
+        self.update_register(6, self.r6.wrapping_add(1)).ok()?;
+        Some(value)
+    }
 }
 
 #[cfg(test)]
 #[allow(clippy::unwrap_used, clippy::as_conversions)]
 mod tests {
     use super::*;
+    use crate::console::BufferedConsole;
 
     #[test]
     fn test_cpu_initialization() {
@@ -417,10 +867,12 @@ mod tests {
         assert_eq!(cpu.r3, 0);
         assert_eq!(cpu.r4, 0);
         assert_eq!(cpu.r5, 0);
-        assert_eq!(cpu.r6, 0);
+        assert_eq!(cpu.r6, 0x3000);
         assert_eq!(cpu.r7, 0);
         assert_eq!(cpu.pc, 0x3000);
-        assert_eq!(cpu.cond, 0);
+        assert_eq!(cpu.cond(), ConditionFlags::ZRO.into());
+        assert!(!cpu.is_user_mode());
+        assert_eq!(cpu.priority(), 0);
         assert!(cpu.running);
     }
 
@@ -436,15 +888,15 @@ mod tests {
         let mut cpu = CPU::new();
         cpu.update_register(0, 0).unwrap();
         cpu.update_flag(0).unwrap();
-        assert_eq!(cpu.cond, ConditionFlags::ZRO.into());
+        assert_eq!(cpu.cond(), ConditionFlags::ZRO.into());
 
         cpu.update_register(0, 1).unwrap();
         cpu.update_flag(0).unwrap();
-        assert_eq!(cpu.cond, ConditionFlags::POS.into());
+        assert_eq!(cpu.cond(), ConditionFlags::POS.into());
 
         cpu.update_register(0, 0xFFFF).unwrap();
         cpu.update_flag(0).unwrap();
-        assert_eq!(cpu.cond, ConditionFlags::NEG.into());
+        assert_eq!(cpu.cond(), ConditionFlags::NEG.into());
     }
 
     #[test]
@@ -521,7 +973,7 @@ mod tests {
     #[test]
     fn test_execute_br() {
         let mut cpu = CPU::new();
-        cpu.cond = ConditionFlags::ZRO.into();
+        cpu.set_cond(ConditionFlags::ZRO);
         let opcode = Opcode::OP_BR {
             n: false,
             z: true,
@@ -619,4 +1071,581 @@ mod tests {
         cpu.execute(opcode).unwrap();
         assert_eq!(cpu.memory.read(0x3002).unwrap(), 0x1234);
     }
+
+    #[test]
+    fn test_execute_trap_out() {
+        let console = BufferedConsole::new();
+        let mut cpu = CPU::with_console(Box::new(console.clone()));
+        cpu.update_register(0, u16::from(b'A')).unwrap();
+        cpu.execute(Opcode::OP_TRAP { trapvec: Trap::Out }).unwrap();
+        assert_eq!(console.output_string(), "A");
+        assert_eq!(cpu.r7, 0x3000);
+    }
+
+    #[test]
+    fn test_execute_trap_puts() {
+        let console = BufferedConsole::new();
+        let mut cpu = CPU::with_console(Box::new(console.clone()));
+        cpu.memory.write(0x4000, u16::from(b'H')).unwrap();
+        cpu.memory.write(0x4001, u16::from(b'i')).unwrap();
+        cpu.update_register(0, 0x4000).unwrap();
+        cpu.execute(Opcode::OP_TRAP {
+            trapvec: Trap::Puts,
+        })
+        .unwrap();
+        assert_eq!(console.output_string(), "Hi");
+    }
+
+    #[test]
+    fn test_execute_trap_putsp() {
+        let console = BufferedConsole::new();
+        let mut cpu = CPU::with_console(Box::new(console.clone()));
+        cpu.memory.write(0x4000, 0x6948).unwrap();
+        cpu.memory.write(0x4001, 0x0021).unwrap();
+        cpu.update_register(0, 0x4000).unwrap();
+        cpu.execute(Opcode::OP_TRAP {
+            trapvec: Trap::Putsp,
+        })
+        .unwrap();
+        assert_eq!(console.output_string(), "Hi!");
+    }
+
+    #[test]
+    fn test_execute_trap_getc() {
+        let console = BufferedConsole::with_input("x");
+        let mut cpu = CPU::with_console(Box::new(console));
+        cpu.execute(Opcode::OP_TRAP {
+            trapvec: Trap::GetC,
+        })
+        .unwrap();
+        assert_eq!(cpu.r0, u16::from(b'x'));
+        assert!(cpu
+            .execute(Opcode::OP_TRAP {
+                trapvec: Trap::GetC,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_step_outcome() {
+        let mut cpu = CPU::new();
+        // LDR R1, R0, #0 ; STR R1, R0, #1
+        cpu.memory.load_program(&[0x3000, 0x6200, 0x7201]).unwrap();
+        cpu.memory.write(0x4000, 0xBEEF).unwrap();
+        cpu.update_register(0, 0x4000).unwrap();
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!(outcome.pc_before, 0x3000);
+        assert_eq!(outcome.pc_after, 0x3001);
+        assert_eq!(outcome.instruction, 0x6200);
+        assert_eq!(
+            outcome.opcode,
+            Opcode::OP_LDR {
+                dr: 1,
+                base_r: 0,
+                offset: 0
+            }
+        );
+        assert_eq!(
+            outcome.register_writes,
+            vec![RegisterWrite {
+                index: 1,
+                old: 0,
+                new: 0xBEEF
+            }]
+        );
+        assert_eq!(
+            outcome.memory_reads,
+            vec![MemoryRead {
+                address: 0x4000,
+                value: 0xBEEF
+            }]
+        );
+        assert!(outcome.memory_writes.is_empty());
+
+        let outcome = cpu.step().unwrap();
+        assert!(outcome.register_writes.is_empty());
+        assert_eq!(
+            outcome.memory_writes,
+            vec![MemoryWrite {
+                address: 0x4001,
+                old: 0,
+                new: 0xBEEF
+            }]
+        );
+        assert!(!outcome.halted);
+    }
+
+    #[test]
+    fn test_step_outcome_trap() {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.memory.load_program(&[0x3000, 0xF025]).unwrap();
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!(outcome.trap, Some(Trap::Halt));
+        assert!(outcome.halted);
+        assert_eq!(
+            outcome.register_writes,
+            vec![RegisterWrite {
+                index: 7,
+                old: 0,
+                new: 0x3001
+            }]
+        );
+    }
+
+    #[test]
+    fn test_run_for_budget() {
+        let mut cpu = CPU::new();
+        // BRnzp #-1
+        cpu.memory.load_program(&[0x3000, 0x0FFF]).unwrap();
+        assert_eq!(cpu.run_for(100).unwrap(), RunOutcome::BudgetExhausted);
+        assert_eq!(cpu.instruction_count, 100);
+        assert_eq!(cpu.pc, 0x3000);
+        assert!(cpu.running);
+    }
+
+    #[test]
+    fn test_run_until_halted() {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        // ADD R0, R0, #1 ; HALT
+        cpu.memory.load_program(&[0x3000, 0x1021, 0xF025]).unwrap();
+        assert_eq!(cpu.run_for(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(cpu.instruction_count, 2);
+    }
+
+    #[test]
+    fn test_execute_program_timeout() {
+        let mut cpu = CPU::new();
+        cpu.memory.load_program(&[0x3000, 0x0FFF]).unwrap();
+        cpu.timeout = Some(Duration::from_millis(10));
+        assert_eq!(cpu.execute_program().unwrap(), RunOutcome::TimedOut);
+        assert!(cpu.running);
+    }
+
+    #[test]
+    fn test_execute_br_tests_each_flag() {
+        let mut cpu = CPU::new();
+        cpu.set_cond(ConditionFlags::POS);
+        let opcode = Opcode::OP_BR {
+            n: true,
+            z: true,
+            p: false,
+            offset: 1,
+        };
+        cpu.execute(opcode).unwrap();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.psr, 0x0001);
+    }
+
+    #[test]
+    fn test_execute_rti_to_user_mode() {
+        let mut cpu = CPU::new();
+        cpu.update_register(6, 0x2FFE).unwrap();
+        cpu.saved_usp = 0xFDFF;
+        cpu.memory.write(0x2FFE, 0x3000).unwrap();
+        cpu.memory.write(0x2FFF, 0x8001).unwrap();
+
+        cpu.execute(Opcode::OP_RTI).unwrap();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.psr, 0x8001);
+        assert!(cpu.is_user_mode());
+        assert_eq!(cpu.r6, 0xFDFF);
+        assert_eq!(cpu.saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn test_execute_rti_in_user_mode_raises_exception() {
+        let mut cpu = CPU::new();
+        cpu.exception_mode = ExceptionMode::Strict;
+        cpu.psr = 0x8004;
+        cpu.pc = 0x3000;
+        cpu.update_register(6, 0xF000).unwrap();
+        cpu.memory.write(0x3000, 0x8000).unwrap();
+        cpu.memory
+            .write(0x0100 | PRIVILEGE_MODE_VECTOR, 0x1000)
+            .unwrap();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x1000);
+        assert!(!cpu.is_user_mode());
+        assert_eq!(cpu.saved_usp, 0xF000);
+        assert_eq!(cpu.r6, 0x2FFE);
+        assert_eq!(cpu.memory.peek(0x2FFF), 0x8004);
+        assert_eq!(cpu.memory.peek(0x2FFE), 0x3001);
+    }
+
+    #[test]
+    fn test_rti_in_user_mode_is_an_error_when_lenient() {
+        let mut cpu = CPU::new();
+        cpu.psr = 0x8004;
+        cpu.memory.write(0x3000, 0x8000).unwrap();
+
+        let err = cpu.step().unwrap_err();
+        assert!(matches!(
+            err,
+            CPUError::PrivilegeViolation {
+                pc: 0x3000,
+                instruction: 0x8000
+            }
+        ));
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let mut cpu = CPU::new();
+        cpu.memory.write(0x3000, 0xD123).unwrap();
+        let err = cpu.step().unwrap_err();
+        assert!(matches!(
+            err,
+            CPUError::IllegalOpcode {
+                pc: 0x3000,
+                instruction: 0xD123
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Illegal opcode at x3000 (instruction xD123)"
+        );
+
+        let mut cpu = CPU::new();
+        cpu.exception_mode = ExceptionMode::Strict;
+        cpu.r6 = 0x3000;
+        cpu.memory.write(0x3000, 0xD123).unwrap();
+        cpu.memory
+            .write(0x0100 | ILLEGAL_OPCODE_VECTOR, 0x1100)
+            .unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x1100);
+        assert_eq!(cpu.memory.peek(0x2FFE), 0x3001);
+    }
+
+    #[test]
+    fn test_strict_exception_without_os_uses_supervisor_stack() {
+        // Nothing sets R6 before the exception, as when running without --os
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.exception_mode = ExceptionMode::Strict;
+        cpu.memory.write(0x3000, 0xD000).unwrap();
+        cpu.memory
+            .write(0x0100 | ILLEGAL_OPCODE_VECTOR, 0x1100)
+            .unwrap();
+        cpu.memory.write(0x1100, 0x8000).unwrap();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x1100);
+        assert!(cpu.running);
+        assert!(cpu.memory.clock_enabled());
+        assert_eq!(cpu.memory.peek(0xFFFF), 0);
+        assert_eq!(cpu.r6, 0x2FFE);
+        assert_eq!(cpu.memory.peek(0x2FFF), 0x0002);
+        assert_eq!(cpu.memory.peek(0x2FFE), 0x3001);
+
+        // The handler returns past the faulting instruction
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x3001);
+        assert_eq!(cpu.r6, 0x3000);
+    }
+
+    #[test]
+    fn test_unknown_trap() {
+        let mut cpu = CPU::new();
+        cpu.memory.write(0x3000, 0xF026).unwrap();
+        let err = cpu.step().unwrap_err();
+        assert!(matches!(
+            err,
+            CPUError::UnknownTrap {
+                pc: 0x3000,
+                instruction: 0xF026
+            }
+        ));
+
+        let mut cpu = CPU::new();
+        cpu.exception_mode = ExceptionMode::Strict;
+        cpu.memory.write(0x3000, 0xF026).unwrap();
+        cpu.memory.write(0x0026, 0x1200).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x1200);
+        assert_eq!(cpu.r7, 0x3001);
+    }
+
+    #[test]
+    fn test_trap_vector_table() {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.trap_mode = TrapMode::VectorTable;
+        cpu.memory.write(0x3000, 0xF025).unwrap();
+        cpu.memory.write(0x0025, 0x0400).unwrap();
+
+        let outcome = cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(cpu.r7, 0x3001);
+        assert_eq!(cpu.r6, 0x3000);
+        assert!(cpu.running);
+        assert_eq!(outcome.trap, Some(Trap::Halt));
+        assert_eq!(
+            outcome.memory_reads,
+            vec![MemoryRead {
+                address: 0x0025,
+                value: 0x0400
+            }]
+        );
+
+        // Unknown vectors go through the table too, even in lenient mode
+        cpu.pc = 0x3000;
+        cpu.memory.write(0x3000, 0xF0FF).unwrap();
+        cpu.memory.write(0x00FF, 0x0500).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0500);
+
+        // From user mode the routine runs privileged on the supervisor stack
+        cpu.pc = 0x3000;
+        cpu.psr = PSR_USER_MODE;
+        cpu.r6 = 0x4000;
+        cpu.memory.write(0x3000, 0xF025).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(cpu.r6, 0x2FFE);
+        assert_eq!(cpu.saved_usp, 0x4000);
+        assert!(!cpu.is_user_mode());
+    }
+
+    #[test]
+    fn test_trap_from_supervisor_mode_returns_with_ret() {
+        let source = "
+            .ORIG x3000
+            LEA R0, ROUTINE
+            STI R0, VECTOR
+            TRAP x30
+            ADD R2, R2, #1
+            HALT
+    ROUTINE ADD R1, R1, #1
+            RET
+    VECTOR  .FILL x0030
+            .END
+        ";
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.trap_mode = TrapMode::VectorTable;
+        let program = crate::assembler::assemble(source).unwrap();
+        cpu.memory.load_program(&program.to_words()).unwrap();
+
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.pc, 0x3005);
+        assert_eq!(cpu.r7, 0x3003);
+        assert_eq!(cpu.r6, 0x3000);
+
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.r1, 1);
+        assert_eq!(cpu.r2, 1);
+        assert_eq!(cpu.r6, 0x3000);
+    }
+
+    #[test]
+    fn test_trap_exception_mode_returns_with_rti() {
+        let source = "
+            .ORIG x3000
+            LEA R0, ROUTINE
+            STI R0, VECTOR
+            TRAP x30
+            ADD R2, R2, #1
+            HALT
+    ROUTINE ADD R1, R1, #1
+            RTI
+    VECTOR  .FILL x0030
+            .END
+        ";
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.trap_mode = TrapMode::Exception;
+        let program = crate::assembler::assemble(source).unwrap();
+        cpu.memory.load_program(&program.to_words()).unwrap();
+
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.pc, 0x3005);
+        assert_eq!(cpu.r6, 0x2FFE);
+        assert_eq!(cpu.memory.peek(0x2FFF), 0x0001);
+        assert_eq!(cpu.memory.peek(0x2FFE), 0x3003);
+
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.r1, 1);
+        assert_eq!(cpu.r2, 1);
+        assert_eq!(cpu.r6, 0x3000);
+        assert!(!cpu.is_user_mode());
+    }
+
+    #[test]
+    fn test_program_drives_display_and_clock() {
+        let source = "
+            .ORIG x3000
+            LD R0, CHAR
+    POLL    LDI R1, DSR
+            BRzp POLL
+            STI R0, DDR
+            LDI R1, MCR
+            LD R2, MASK
+            AND R1, R1, R2
+            STI R1, MCR
+            ADD R3, R3, #1
+    CHAR    .FILL x0041
+    DSR     .FILL xFE04
+    DDR     .FILL xFE06
+    MCR     .FILL xFFFE
+    MASK    .FILL x7FFF
+            .END
+        ";
+        let console = BufferedConsole::new();
+        let mut cpu = CPU::with_console(Box::new(console.clone()));
+        let program = crate::assembler::assemble(source).unwrap();
+        cpu.memory.load_program(&program.to_words()).unwrap();
+
+        assert_eq!(cpu.run_for(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(console.output_string(), "A");
+        assert_eq!(cpu.instruction_count, 8);
+        assert_eq!(cpu.r3, 0);
+    }
+
+    #[test]
+    fn test_timer_interrupts_every_interval() {
+        let source = "
+            .ORIG x3000
+            LD R6, STACK
+            LEA R0, HANDLER
+            STI R0, VECTOR
+            AND R0, R0, #0
+            ADD R0, R0, #10
+            STI R0, TMI
+            LD R0, ENABLE
+            STI R0, TMR
+    LOOP    ADD R1, R1, #1
+            BR LOOP
+    HANDLER ADD R2, R2, #1
+            LDI R0, TMR
+            RTI
+    STACK   .FILL x2FFF
+    VECTOR  .FILL x0181
+    TMI     .FILL xFE0A
+    TMR     .FILL xFE08
+    ENABLE  .FILL x4000
+            .END
+        ";
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        let program = crate::assembler::assemble(source).unwrap();
+        cpu.memory.load_program(&program.to_words()).unwrap();
+
+        // 8 instructions of setup, then an interrupt every 10 instructions
+        // and 3 more for each handler
+        assert_eq!(
+            cpu.run_for(8 + 3 * 13).unwrap(),
+            RunOutcome::BudgetExhausted
+        );
+        assert_eq!(cpu.r2, 3);
+        assert_eq!(cpu.priority(), 0);
+    }
+
+    #[test]
+    fn test_user_mode_access_violation() {
+        let mut cpu = CPU::new();
+        cpu.psr = 0x8002;
+        // LDR R0, R1, #0 with R1 pointing at KBSR
+        cpu.memory.write(0x3000, 0x6040).unwrap();
+        cpu.r1 = 0xFE00;
+        let err = cpu.step().unwrap_err();
+        assert!(matches!(
+            err,
+            CPUError::AccessViolation {
+                pc: 0x3000,
+                instruction: 0x6040,
+                address: 0xFE00
+            }
+        ));
+
+        // ST R0, #-256 into system space is fine in supervisor mode
+        cpu.pc = 0x3000;
+        cpu.memory.write(0x3000, 0x3100).unwrap();
+        cpu.r0 = 0x1234;
+        cpu.psr = 0x0002;
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.peek(0x2F01), 0x1234);
+    }
+
+    #[test]
+    fn test_fetch_access_violation() {
+        let mut cpu = CPU::new();
+        cpu.psr = 0x8002;
+        cpu.pc = 0x0200;
+        cpu.memory.write(0x0200, 0x1021).unwrap();
+        let err = cpu.step().unwrap_err();
+        assert!(matches!(
+            err,
+            CPUError::AccessViolation {
+                pc: 0x0200,
+                instruction: 0x1021,
+                address: 0x0200
+            }
+        ));
+
+        cpu.exception_mode = ExceptionMode::Strict;
+        cpu.saved_ssp = 0x3000;
+        cpu.memory
+            .write(0x0100 | ACCESS_CONTROL_VECTOR, 0x1000)
+            .unwrap();
+        cpu.memory.write(0x1000, 0x1021).unwrap();
+        cpu.step().unwrap();
+        // The handler's first instruction ran in supervisor mode
+        assert_eq!(cpu.pc, 0x1001);
+        assert_eq!(cpu.r0, 1);
+        assert_eq!(cpu.memory.peek(0x2FFE), 0x0200);
+        assert_eq!(cpu.memory.peek(0x2FFF), 0x8002);
+    }
+
+    #[test]
+    fn test_interrupt_raises_priority() {
+        let mut cpu = CPU::new();
+        cpu.psr = 0x8002;
+        cpu.memory.write(0x0180, 0x1200).unwrap();
+
+        cpu.enter_interrupt(0x80, 4).unwrap();
+        assert_eq!(cpu.pc, 0x1200);
+        assert_eq!(cpu.priority(), 4);
+        assert_eq!(cpu.psr, 0x0402);
+        assert_eq!(cpu.memory.peek(0x2FFF), 0x8002);
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        let source = "
+            .ORIG x3000
+            LD R6, STACK
+            LD R0, ISR_ADDR
+            STI R0, VECTOR
+            LD R0, ENABLE
+            STI R0, KBSR
+    LOOP    ADD R1, R1, #0
+            BRz LOOP
+            HALT
+    ISR     LDI R1, KBDR
+            RTI
+    STACK   .FILL x2FF0
+    ISR_ADDR .FILL ISR
+    VECTOR  .FILL x0180
+    ENABLE  .FILL x4000
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+            .END
+        ";
+        let program = crate::assembler::assemble(source).unwrap();
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::with_input("k")));
+        cpu.memory.load_program(&program.to_words()).unwrap();
+
+        let mut interrupts = 0;
+        while cpu.running {
+            let outcome = cpu.step().unwrap();
+            if outcome.interrupt.is_some() {
+                interrupts += 1;
+                assert_eq!(outcome.pc_before, 0x3005);
+                assert_eq!(outcome.opcode.to_string(), "LDI R1, #6");
+            }
+        }
+        assert_eq!(interrupts, 1);
+        assert_eq!(cpu.r1, u16::from(b'k'));
+        assert_eq!(cpu.r6, 0x2FF0);
+        assert_eq!(cpu.priority(), 0);
+    }
 }
@@ -0,0 +1,700 @@
+use crate::console::BufferedConsole;
+use crate::opcode::Opcode;
+use crate::undo::{UndoRecord, DEFAULT_UNDO_CAPACITY};
+use crate::vm::{Vm, VmError};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DebuggerError {
+    #[error("Unknown command '{0}', type 'help' for the list of commands")]
+    UnknownCommand(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("The program's input does not come from the debugger")]
+    NoInput,
+    #[error(transparent)]
+    Vm(#[from] VmError),
+}
+
+/// A register or a memory cell that can be inspected or written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Register(u16),
+    Pc,
+    Psr,
+    Memory(u16),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Step(u32),
+    Continue,
+    ReverseStep(u32),
+    ReverseContinue(Option<Location>),
+    Break(u16),
+    Delete(u16),
+    Breakpoints,
+    Registers,
+    Print(Location),
+    Examine(u16, u16),
+    Set(Location, u16),
+    List(Option<u16>),
+    Input(String),
+    Help,
+    Quit,
+}
+
+const HELP: &str = "\
+step [n]           execute n instructions (default 1)
+continue           run until a breakpoint or HALT
+reverse-step [n]   undo n instructions (default 1)
+reverse-continue [loc]
+                   run backwards to a breakpoint, or to the last write of loc
+break <addr>       set a breakpoint
+delete <addr>      remove a breakpoint
+breakpoints        list breakpoints
+regs               show all registers
+print <loc>        show r0..r7, pc, psr, cond or a memory address
+x <addr> [count]   dump memory
+set <loc> <value>  write a register or memory address
+list [addr]        disassemble around pc or addr
+input <text>       queue a line of keyboard input for the program
+quit               exit the debugger";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, DebuggerError> {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(Command::Step(1));
+        };
+        if name == "input" {
+            // Keeps the text as typed, inner spaces included
+            let text = line.trim_start().trim_start_matches(name);
+            let text = text.strip_prefix(' ').unwrap_or(text);
+            return Ok(Command::Input(
+                text.trim_end_matches(['\n', '\r']).to_string(),
+            ));
+        }
+        let args: Vec<&str> = words.collect();
+
+        let command = match (name, args.as_slice()) {
+            ("s" | "step", []) => Command::Step(1),
+            ("s" | "step", [count]) => Command::Step(
+                count
+                    .parse()
+                    .map_err(|_| DebuggerError::InvalidArgument(count.to_string()))?,
+            ),
+            ("c" | "continue", []) => Command::Continue,
+            ("rs" | "reverse-step", []) => Command::ReverseStep(1),
+            ("rs" | "reverse-step", [count]) => Command::ReverseStep(
+                count
+                    .parse()
+                    .map_err(|_| DebuggerError::InvalidArgument(count.to_string()))?,
+            ),
+            ("rc" | "reverse-continue", []) => Command::ReverseContinue(None),
+            ("rc" | "reverse-continue", [location]) => {
+                Command::ReverseContinue(Some(parse_location(location)?))
+            }
+            ("b" | "break", [address]) => Command::Break(parse_value(address)?),
+            ("d" | "delete", [address]) => Command::Delete(parse_value(address)?),
+            ("bl" | "breakpoints", []) => Command::Breakpoints,
+            ("r" | "regs", []) => Command::Registers,
+            ("p" | "print", [location]) => Command::Print(parse_location(location)?),
+            ("x", [address]) => Command::Examine(parse_value(address)?, 1),
+            ("x", [address, count]) => Command::Examine(parse_value(address)?, parse_value(count)?),
+            ("set", [location, value]) => {
+                Command::Set(parse_location(location)?, parse_value(value)?)
+            }
+            ("l" | "list", []) => Command::List(None),
+            ("l" | "list", [address]) => Command::List(Some(parse_value(address)?)),
+            ("h" | "help", []) => Command::Help,
+            ("q" | "quit", []) => Command::Quit,
+            _ => return Err(DebuggerError::UnknownCommand(line.trim().to_string())),
+        };
+
+        Ok(command)
+    }
+}
+
+// Values are written as xhex, 0xhex, #decimal or plain decimal.
+fn parse_value(text: &str) -> Result<u16, DebuggerError> {
+    let invalid = || DebuggerError::InvalidArgument(text.to_string());
+    let parsed = if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('x'))
+        .or_else(|| text.strip_prefix('X'))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        let decimal = text.strip_prefix('#').unwrap_or(text);
+        decimal.parse::<u16>().ok().or_else(|| {
+            decimal
+                .parse::<i16>()
+                .ok()
+                .map(|value| u16::from_ne_bytes(value.to_ne_bytes()))
+        })
+    };
+    parsed.ok_or_else(invalid)
+}
+
+fn parse_location(text: &str) -> Result<Location, DebuggerError> {
+    let lower = text.to_lowercase();
+    match lower.as_str() {
+        "pc" => Ok(Location::Pc),
+        "psr" | "cond" => Ok(Location::Psr),
+        _ => match lower.as_bytes() {
+            [b'r', digit @ b'0'..=b'7'] => {
+                Ok(Location::Register(u16::from(digit.wrapping_sub(b'0'))))
+            }
+            _ => parse_value(text).map(Location::Memory),
+        },
+    }
+}
+
+/// Interactive debugger driving a `Vm` one instruction at a time.
+pub struct Debugger {
+    vm: Vm,
+    breakpoints: BTreeSet<u16>,
+    console: Option<BufferedConsole>,
+}
+
+impl Debugger {
+    /// Turns on the VM's undo log, if it is off, so execution can be reversed.
+    pub fn new(mut vm: Vm) -> Self {
+        if vm.cpu().undo_log.is_none() {
+            vm.set_undo_capacity(Some(DEFAULT_UNDO_CAPACITY));
+        }
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+            console: None,
+        }
+    }
+
+    /// Debugs a VM whose console is `console` or a clone of it. The program
+    /// reads what the `input` command queues, and `run` prints its output.
+    pub fn with_console(vm: Vm, console: BufferedConsole) -> Self {
+        Self {
+            console: Some(console),
+            ..Self::new(vm)
+        }
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    /// Reads commands from `input` until `quit` or end of input.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        loop {
+            write!(output, "(lc3) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            match Command::parse(&line) {
+                Ok(Command::Quit) => return Ok(()),
+                Ok(command) => {
+                    let result = self.execute(command);
+                    self.write_program_output(&mut output)?;
+                    match result {
+                        Ok(text) => writeln!(output, "{}", text)?,
+                        Err(err) => writeln!(output, "{}", err)?,
+                    }
+                }
+                Err(err) => writeln!(output, "{}", err)?,
+            }
+        }
+    }
+
+    fn write_program_output<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let Some(console) = &self.console else {
+            return Ok(());
+        };
+        let bytes = console.take_output();
+        if !bytes.is_empty() {
+            output.write_all(&bytes)?;
+            if !bytes.ends_with(b"\n") {
+                writeln!(output)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, command: Command) -> Result<String, DebuggerError> {
+        let text = match command {
+            Command::Step(count) => {
+                for _ in 0..count {
+                    if !self.vm.is_running() {
+                        break;
+                    }
+                    self.step()?;
+                }
+                self.location()
+            }
+            Command::Continue => {
+                if self.vm.is_running() {
+                    self.step()?;
+                }
+                while self.vm.is_running() && !self.breakpoints.contains(&self.vm.pc()) {
+                    self.step()?;
+                }
+                if self.vm.is_running() {
+                    format!("Breakpoint at x{:04X}\n{}", self.vm.pc(), self.location())
+                } else {
+                    self.location()
+                }
+            }
+            Command::ReverseStep(count) => {
+                for _ in 0..count {
+                    if self.vm.step_back()?.is_none() {
+                        return Ok(format!("{}\n{}", START_OF_LOG, self.location()));
+                    }
+                }
+                self.location()
+            }
+            Command::ReverseContinue(target) => self.reverse_continue(target)?,
+            Command::Break(address) => {
+                self.breakpoints.insert(address);
+                format!("Breakpoint set at x{:04X}", address)
+            }
+            Command::Delete(address) => {
+                if self.breakpoints.remove(&address) {
+                    format!("Breakpoint removed at x{:04X}", address)
+                } else {
+                    format!("No breakpoint at x{:04X}", address)
+                }
+            }
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    "No breakpoints".to_string()
+                } else {
+                    self.breakpoints
+                        .iter()
+                        .map(|address| format!("x{:04X}", address))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Command::Registers => self.registers()?,
+            Command::Print(location) => {
+                let value = self.read(location)?;
+                format!("{} = x{:04X} ({})", describe(location), value, value)
+            }
+            Command::Examine(address, count) => {
+                let mut text = String::new();
+                for offset in 0..count {
+                    let address = address.wrapping_add(offset);
+                    let _ = writeln!(
+                        text,
+                        "x{:04X}  x{:04X}",
+                        address,
+                        self.vm.read_memory(address)
+                    );
+                }
+                text.trim_end().to_string()
+            }
+            Command::Set(location, value) => {
+                self.write(location, value)?;
+                format!("{} = x{:04X}", describe(location), value)
+            }
+            Command::List(address) => self.list(address.unwrap_or(self.vm.pc())),
+            Command::Input(text) => {
+                let console = self.console.as_ref().ok_or(DebuggerError::NoInput)?;
+                console.push_input(format!("{}\n", text));
+                format!("Queued {} bytes of input", text.len().saturating_add(1))
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        };
+
+        Ok(text)
+    }
+
+    // A failed instruction is undone, so it can be retried after e.g. queueing
+    // the input it was waiting for
+    fn step(&mut self) -> Result<(), DebuggerError> {
+        if let Err(err) = self.vm.step() {
+            self.vm.step_back()?;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    // Stops before the instruction that wrote `target`, or at a breakpoint
+    fn reverse_continue(&mut self, target: Option<Location>) -> Result<String, DebuggerError> {
+        loop {
+            let after = target.map(|location| self.read(location)).transpose()?;
+            let Some(record) = self.vm.step_back()? else {
+                return Ok(format!("{}\n{}", START_OF_LOG, self.location()));
+            };
+            match (target, after) {
+                (Some(location), Some(after)) if wrote(&record, location, after) => {
+                    return Ok(format!(
+                        "{} last written by x{:04X}\n{}",
+                        describe(location),
+                        self.vm.pc(),
+                        self.location()
+                    ));
+                }
+                (None, _) if self.breakpoints.contains(&self.vm.pc()) => {
+                    return Ok(format!(
+                        "Breakpoint at x{:04X}\n{}",
+                        self.vm.pc(),
+                        self.location()
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn read(&self, location: Location) -> Result<u16, DebuggerError> {
+        let value = match location {
+            Location::Register(index) => self.vm.register(index)?,
+            Location::Pc => self.vm.pc(),
+            Location::Psr => self.vm.psr(),
+            Location::Memory(address) => self.vm.read_memory(address),
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, location: Location, value: u16) -> Result<(), DebuggerError> {
+        match location {
+            Location::Register(index) => self.vm.set_register(index, value)?,
+            Location::Pc => self.vm.set_pc(value),
+            Location::Psr => self.vm.cpu_mut().psr = value,
+            Location::Memory(address) => self.vm.write_memory(address, value)?,
+        }
+        Ok(())
+    }
+
+    fn registers(&self) -> Result<String, DebuggerError> {
+        let mut rows = Vec::new();
+        for indexes in [0..4, 4..8] {
+            let row = indexes
+                .map(|index| Ok(format!("R{} x{:04X}", index, self.vm.register(index)?)))
+                .collect::<Result<Vec<_>, DebuggerError>>()?;
+            rows.push(row.join("  "));
+        }
+        let cond = self.vm.cond();
+        let flags: String = [(0b100, 'N'), (0b010, 'Z'), (0b001, 'P')]
+            .iter()
+            .map(|(bit, flag)| if cond & bit != 0 { *flag } else { '-' })
+            .collect();
+        let mode = if self.vm.cpu().is_user_mode() {
+            "user"
+        } else {
+            "supervisor"
+        };
+        rows.push(format!(
+            "PC x{:04X}  PSR x{:04X}  CC {}  {} mode",
+            self.vm.pc(),
+            self.vm.psr(),
+            flags,
+            mode
+        ));
+        Ok(rows.join("\n"))
+    }
+
+    fn location(&self) -> String {
+        if !self.vm.is_running() {
+            return "Program halted".to_string();
+        }
+        self.disassemble(self.vm.pc())
+    }
+
+    fn disassemble(&self, address: u16) -> String {
+        let word = self.vm.read_memory(address);
+        let text = match Opcode::from(word) {
+            Ok(opcode) => opcode.at(address).to_string(),
+            Err(_) => format!(".FILL x{:04X}", word),
+        };
+        let marker = if address == self.vm.pc() { '>' } else { ' ' };
+        let breakpoint = if self.breakpoints.contains(&address) {
+            '*'
+        } else {
+            ' '
+        };
+        format!(
+            "{}{} x{:04X}  x{:04X}  {}",
+            marker, breakpoint, address, word, text
+        )
+    }
+
+    fn list(&self, center: u16) -> String {
+        (0..9u16)
+            .map(|offset| self.disassemble(center.wrapping_sub(3).wrapping_add(offset)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+const START_OF_LOG: &str = "Reached the start of the undo log";
+
+fn wrote(record: &UndoRecord, location: Location, after: u16) -> bool {
+    match location {
+        Location::Register(index) => record.wrote_register(index),
+        // Every instruction moves the PC; only jumps, taken branches, traps
+        // and interrupts write it
+        Location::Pc => after != record.pc.wrapping_add(1),
+        Location::Psr => record.psr != after,
+        Location::Memory(address) => record.wrote_memory(address),
+    }
+}
+
+fn describe(location: Location) -> String {
+    match location {
+        Location::Register(index) => format!("R{}", index),
+        Location::Pc => "PC".to_string(),
+        Location::Psr => "PSR".to_string(),
+        Location::Memory(address) => format!("x{:04X}", address),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferedConsole;
+
+    fn debugger(source: &str) -> Debugger {
+        let mut vm = Vm::with_console(BufferedConsole::new());
+        vm.load_program(&assemble(source).unwrap().to_words())
+            .unwrap();
+        Debugger::new(vm)
+    }
+
+    const COUNTDOWN: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+        ADD R0, R0, #3
+LOOP    ADD R0, R0, #-1
+        BRp LOOP
+        HALT
+        .END
+    ";
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("").unwrap(), Command::Step(1));
+        assert_eq!(Command::parse("step 5").unwrap(), Command::Step(5));
+        assert_eq!(Command::parse("b x3002").unwrap(), Command::Break(0x3002));
+        assert_eq!(
+            Command::parse("print R3").unwrap(),
+            Command::Print(Location::Register(3))
+        );
+        assert_eq!(
+            Command::parse("set x4000 #-1").unwrap(),
+            Command::Set(Location::Memory(0x4000), 0xFFFF)
+        );
+        assert!(matches!(
+            Command::parse("jump"),
+            Err(DebuggerError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            Command::parse("print r9"),
+            Err(DebuggerError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_step_and_inspect() {
+        let mut debugger = debugger(COUNTDOWN);
+        debugger.execute(Command::Step(2)).unwrap();
+        assert_eq!(debugger.vm().register(0).unwrap(), 3);
+        assert_eq!(
+            debugger
+                .execute(Command::Print(Location::Register(0)))
+                .unwrap(),
+            "R0 = x0003 (3)"
+        );
+        assert_eq!(
+            debugger
+                .execute(Command::List(None))
+                .unwrap()
+                .lines()
+                .nth(3),
+            Some(">  x3002  x103F  ADD R0, R0, #-1")
+        );
+    }
+
+    #[test]
+    fn test_continue_to_breakpoint() {
+        let mut debugger = debugger(COUNTDOWN);
+        debugger.execute(Command::Break(0x3003)).unwrap();
+
+        let text = debugger.execute(Command::Continue).unwrap();
+        assert!(text.starts_with("Breakpoint at x3003"));
+        assert_eq!(debugger.vm().register(0).unwrap(), 2);
+
+        debugger.execute(Command::Continue).unwrap();
+        assert_eq!(debugger.vm().register(0).unwrap(), 1);
+
+        debugger.execute(Command::Delete(0x3003)).unwrap();
+        let text = debugger.execute(Command::Continue).unwrap();
+        assert_eq!(text, "Program halted");
+        assert_eq!(debugger.vm().register(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_set_memory_and_registers() {
+        let mut debugger = debugger(COUNTDOWN);
+        debugger
+            .execute(Command::Set(Location::Memory(0x4000), 0xBEEF))
+            .unwrap();
+        debugger
+            .execute(Command::Set(Location::Pc, 0x3002))
+            .unwrap();
+        assert_eq!(debugger.vm().read_memory(0x4000), 0xBEEF);
+        assert_eq!(debugger.vm().pc(), 0x3002);
+        assert_eq!(
+            debugger.execute(Command::Examine(0x4000, 1)).unwrap(),
+            "x4000  xBEEF"
+        );
+    }
+
+    const STORE: &str = "
+        .ORIG x3000
+        AND R1, R1, #0
+        ADD R1, R1, #7
+        ST R1, SLOT
+        AND R2, R2, #0
+        ADD R2, R2, #1
+        HALT
+SLOT    .FILL x0
+        .END
+    ";
+
+    #[test]
+    fn test_reverse_to_last_write() {
+        let mut debugger = debugger(STORE);
+        assert_eq!(
+            Command::parse("rc x3006").unwrap(),
+            Command::ReverseContinue(Some(Location::Memory(0x3006)))
+        );
+        assert_eq!(
+            Command::parse("reverse-step 2").unwrap(),
+            Command::ReverseStep(2)
+        );
+        debugger.execute(Command::Continue).unwrap();
+        assert_eq!(debugger.vm().read_memory(0x3006), 7);
+
+        let text = debugger
+            .execute(Command::ReverseContinue(Some(Location::Memory(0x3006))))
+            .unwrap();
+        assert_eq!(
+            text,
+            "x3006 last written by x3002\n>  x3002  x3203  ST R1, x3006"
+        );
+        assert_eq!(debugger.vm().read_memory(0x3006), 0);
+        assert_eq!(debugger.vm().register(2).unwrap(), 0);
+
+        let text = debugger
+            .execute(Command::ReverseContinue(Some(Location::Register(1))))
+            .unwrap();
+        assert!(text.starts_with("R1 last written by x3001"));
+        assert_eq!(debugger.vm().register(1).unwrap(), 0);
+
+        let text = debugger.execute(Command::ReverseStep(5)).unwrap();
+        assert!(text.starts_with("Reached the start of the undo log"));
+        assert_eq!(debugger.vm().pc(), 0x3000);
+
+        // Forward again from the rewound state
+        debugger.execute(Command::Continue).unwrap();
+        assert_eq!(debugger.vm().read_memory(0x3006), 7);
+        assert!(!debugger.vm().is_running());
+    }
+
+    #[test]
+    fn test_reverse_to_last_jump() {
+        let mut debugger = debugger(COUNTDOWN);
+        debugger.execute(Command::Continue).unwrap();
+
+        let text = debugger
+            .execute(Command::ReverseContinue(Some(Location::Pc)))
+            .unwrap();
+        assert_eq!(text, "PC last written by x3003\n>  x3003  x03FE  BRp x3002");
+        assert_eq!(debugger.vm().register(0).unwrap(), 1);
+        assert_eq!(debugger.vm().undo_depth(), 5);
+    }
+
+    #[test]
+    fn test_reverse_continue_to_breakpoint() {
+        let mut debugger = debugger(STORE);
+        debugger.execute(Command::Continue).unwrap();
+        debugger.execute(Command::Break(0x3003)).unwrap();
+
+        let text = debugger.execute(Command::ReverseContinue(None)).unwrap();
+        assert!(text.starts_with("Breakpoint at x3003"));
+        assert_eq!(debugger.vm().instruction_count(), 3);
+        assert_eq!(debugger.vm().undo_depth(), 3);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut debugger = debugger(COUNTDOWN);
+        let mut output = Vec::new();
+        debugger
+            .run("step\nbogus\nregs\nquit\nstep\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Unknown command 'bogus'"));
+        assert!(output.contains("R0 x0000  R1 x0000  R2 x0000  R3 x0000\n"));
+        assert_eq!(debugger.vm().pc(), 0x3001);
+    }
+
+    #[test]
+    fn test_repl_feeds_program_input() {
+        let source = "
+            .ORIG x3000
+            GETC
+            OUT
+            HALT
+            .END
+        ";
+        let console = BufferedConsole::new();
+        let mut vm = Vm::with_console(console.clone());
+        vm.load_program(&assemble(source).unwrap().to_words())
+            .unwrap();
+        let mut debugger = Debugger::with_console(vm, console);
+        assert_eq!(
+            Command::parse("input  a b\n").unwrap(),
+            Command::Input(" a b".to_string())
+        );
+
+        let mut output = Vec::new();
+        debugger
+            .run("step\ninput q\nstep\nstep\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines
+            .get(1)
+            .unwrap()
+            .contains("Failed to read the keyboard"));
+        assert_eq!(lines.get(2).unwrap(), &"(lc3) Queued 2 bytes of input");
+        assert_eq!(lines.get(4).unwrap(), &"(lc3) q");
+        // The failed GETC was undone and read the key when retried
+        assert_eq!(debugger.vm().register(0).unwrap(), u16::from(b'q'));
+        assert_eq!(debugger.vm().pc(), 0x3002);
+
+        let mut plain = Debugger::new(Vm::with_console(BufferedConsole::new()));
+        assert!(matches!(
+            plain.execute(Command::Input("x".to_string())),
+            Err(DebuggerError::NoInput)
+        ));
+    }
+}
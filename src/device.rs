@@ -0,0 +1,509 @@
+use crate::console::Console;
+use crate::interrupt::{
+    InterruptRequest, KEYBOARD_PRIORITY, KEYBOARD_VECTOR, TIMER_PRIORITY, TIMER_VECTOR,
+};
+use crate::memory::MemoryError;
+use std::time::{Duration, Instant};
+
+pub const MR_KBSR: u16 = 0xFE00; /* keyboard status */
+pub const MR_KBDR: u16 = 0xFE02; /* keyboard data */
+pub const MR_DSR: u16 = 0xFE04; /* display status */
+pub const MR_DDR: u16 = 0xFE06; /* display data */
+pub const MR_TMR: u16 = 0xFE08; /* timer status and control */
+pub const MR_TMI: u16 = 0xFE0A; /* timer interval */
+pub const MR_MCR: u16 = 0xFFFE; /* machine control */
+
+pub const KBSR_READY: u16 = 1 << 15;
+pub const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const DSR_READY: u16 = 1 << 15;
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+pub const TMR_EXPIRED: u16 = 1 << 15;
+pub const TMR_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const TMR_WALL_CLOCK: u16 = 1;
+
+/// A peripheral whose registers are mapped into the address space.
+///
+/// `Memory` forwards every access inside the range the device was mapped at,
+/// passing the absolute address and the console so devices can do I/O.
+pub trait Device {
+    fn read(&mut self, address: u16, console: &mut dyn Console) -> Result<u16, MemoryError>;
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        console: &mut dyn Console,
+    ) -> Result<(), MemoryError>;
+
+    /// The register value without any side effect, for debuggers and dumps.
+    fn peek(&self, address: u16) -> u16;
+
+    /// Checked before every instruction fetch.
+    fn interrupt_request(&mut self, _console: &mut dyn Console) -> Option<InterruptRequest> {
+        None
+    }
+
+    /// Called after every executed instruction.
+    fn tick(&mut self) {}
+
+    /// Whether `tick` or `interrupt_request` may change the device's state
+    /// right now. The undo log saves active devices before every instruction;
+    /// others only when the instruction accesses their registers.
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    /// Internal state for machine snapshots. Stateless devices save nothing.
+    fn save_state(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Restores what `save_state` returned.
+    fn load_state(&mut self, _state: &[u16]) -> Result<(), MemoryError> {
+        Ok(())
+    }
+}
+
+fn saved_words<const N: usize>(device: &str, state: &[u16]) -> Result<[u16; N], MemoryError> {
+    <[u16; N]>::try_from(state).map_err(|_| {
+        MemoryError::DeviceState(format!(
+            "{} expects {} words, got {}",
+            device,
+            N,
+            state.len()
+        ))
+    })
+}
+
+/// KBSR and KBDR. A key read from the console stays latched in KBDR until the
+/// program reads it.
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    status: u16,
+    data: u16,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Only poll while no key is pending
+    fn poll(&mut self, console: &mut dyn Console) -> Result<(), MemoryError> {
+        if self.status & KBSR_READY != 0 {
+            return Ok(());
+        }
+
+        if let Some(key) = console
+            .poll_byte()
+            .map_err(|err| MemoryError::KeyboardInput(err.to_string()))?
+        {
+            self.status |= KBSR_READY;
+            self.data = u16::from(key);
+        }
+
+        Ok(())
+    }
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, address: u16, console: &mut dyn Console) -> Result<u16, MemoryError> {
+        match address {
+            MR_KBSR => {
+                self.poll(console)?;
+                Ok(self.status)
+            }
+            MR_KBDR => {
+                // Reading the data register consumes the key
+                self.status &= !KBSR_READY;
+                Ok(self.data)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        _console: &mut dyn Console,
+    ) -> Result<(), MemoryError> {
+        match address {
+            // READY is set only by a keypress
+            MR_KBSR => {
+                self.status =
+                    (self.status & !KBSR_INTERRUPT_ENABLE) | (value & KBSR_INTERRUPT_ENABLE);
+            }
+            MR_KBDR => self.data = value,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            MR_KBSR => self.status,
+            MR_KBDR => self.data,
+            _ => 0,
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.status, self.data]
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), MemoryError> {
+        [self.status, self.data] = saved_words("keyboard", state)?;
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        self.status & KBSR_INTERRUPT_ENABLE != 0
+    }
+
+    // Input is polled here so interrupt-driven programs never read KBSR.
+    fn interrupt_request(&mut self, console: &mut dyn Console) -> Option<InterruptRequest> {
+        if self.status & KBSR_INTERRUPT_ENABLE == 0 {
+            return None;
+        }
+        self.poll(console).ok()?;
+
+        if self.status & KBSR_READY != 0 {
+            Some(InterruptRequest {
+                vector: KEYBOARD_VECTOR,
+                priority: KEYBOARD_PRIORITY,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// DSR and DDR. Console writes are synchronous, so the display is always ready.
+#[derive(Debug, Default)]
+pub struct Display {
+    status: u16,
+    data: u16,
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Display {
+    fn read(&mut self, address: u16, _console: &mut dyn Console) -> Result<u16, MemoryError> {
+        Ok(self.peek(address))
+    }
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        console: &mut dyn Console,
+    ) -> Result<(), MemoryError> {
+        match address {
+            MR_DSR => self.status = value,
+            MR_DDR => {
+                self.data = value;
+                // Written like the native traps: the char with that code point
+                let [_, byte] = value.to_be_bytes();
+                console
+                    .write_bytes(char::from(byte).to_string().as_bytes())
+                    .and_then(|_| console.flush())
+                    .map_err(|_| MemoryError::Display)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            MR_DSR => self.status | DSR_READY,
+            MR_DDR => self.data,
+            _ => 0,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        false
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.status, self.data]
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), MemoryError> {
+        [self.status, self.data] = saved_words("display", state)?;
+        Ok(())
+    }
+}
+
+/// MCR. The CPU stops once bit 15 is cleared.
+#[derive(Debug)]
+pub struct MachineControl {
+    value: u16,
+}
+
+impl Default for MachineControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MachineControl {
+    pub fn new() -> Self {
+        Self {
+            value: MCR_CLOCK_ENABLE,
+        }
+    }
+}
+
+impl Device for MachineControl {
+    fn read(&mut self, address: u16, _console: &mut dyn Console) -> Result<u16, MemoryError> {
+        Ok(self.peek(address))
+    }
+
+    fn write(
+        &mut self,
+        _address: u16,
+        value: u16,
+        _console: &mut dyn Console,
+    ) -> Result<(), MemoryError> {
+        self.value = value;
+        Ok(())
+    }
+
+    fn peek(&self, _address: u16) -> u16 {
+        self.value
+    }
+
+    fn is_active(&self) -> bool {
+        false
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.value]
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), MemoryError> {
+        [self.value] = saved_words("machine control", state)?;
+        Ok(())
+    }
+}
+
+/// TMR and TMI. Every TMI instructions, or TMI milliseconds when TMR[0] is
+/// set, the timer sets TMR[15] and, if TMR[14] is set, requests an interrupt.
+/// Reading TMR acknowledges it. A zero interval stops the timer.
+#[derive(Debug)]
+pub struct Timer {
+    control: u16,
+    interval: u16,
+    expired: bool,
+    remaining: u16,
+    deadline: Option<Instant>,
+    vector: u16,
+    priority: u16,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::with_interrupt(TIMER_VECTOR, TIMER_PRIORITY)
+    }
+
+    /// A timer that interrupts through `vector` at `priority`.
+    pub fn with_interrupt(vector: u16, priority: u16) -> Self {
+        Self {
+            control: 0,
+            interval: 0,
+            expired: false,
+            remaining: 0,
+            deadline: None,
+            vector,
+            priority,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.remaining = self.interval;
+        self.deadline = Instant::now().checked_add(self.period());
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_millis(self.interval.into())
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, address: u16, _console: &mut dyn Console) -> Result<u16, MemoryError> {
+        let value = self.peek(address);
+        if address == MR_TMR {
+            self.expired = false;
+        }
+        Ok(value)
+    }
+
+    fn write(
+        &mut self,
+        address: u16,
+        value: u16,
+        _console: &mut dyn Console,
+    ) -> Result<(), MemoryError> {
+        match address {
+            MR_TMR => {
+                self.control = value & (TMR_INTERRUPT_ENABLE | TMR_WALL_CLOCK);
+                self.restart();
+            }
+            MR_TMI => {
+                self.interval = value;
+                self.restart();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            MR_TMR if self.expired => self.control | TMR_EXPIRED,
+            MR_TMR => self.control,
+            MR_TMI => self.interval,
+            _ => 0,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.interval != 0
+    }
+
+    fn interrupt_request(&mut self, _console: &mut dyn Console) -> Option<InterruptRequest> {
+        (self.expired && self.control & TMR_INTERRUPT_ENABLE != 0).then_some(InterruptRequest {
+            vector: self.vector,
+            priority: self.priority,
+        })
+    }
+
+    fn tick(&mut self) {
+        if self.interval == 0 {
+            return;
+        }
+
+        if self.control & TMR_WALL_CLOCK != 0 {
+            let now = Instant::now();
+            if self.deadline.is_some_and(|deadline| now >= deadline) {
+                self.expired = true;
+                self.deadline = now.checked_add(self.period());
+            }
+        } else {
+            self.remaining = self.remaining.saturating_sub(1);
+            if self.remaining == 0 {
+                self.expired = true;
+                self.remaining = self.interval;
+            }
+        }
+    }
+
+    // A wall-clock deadline cannot be saved, so it restarts on load.
+    fn save_state(&self) -> Vec<u16> {
+        vec![
+            self.control,
+            self.interval,
+            u16::from(self.expired),
+            self.remaining,
+        ]
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), MemoryError> {
+        let [control, interval, expired, remaining] = saved_words("timer", state)?;
+        self.control = control;
+        self.interval = interval;
+        self.expired = expired != 0;
+        self.remaining = remaining;
+        self.deadline = Instant::now().checked_add(self.period());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::console::BufferedConsole;
+
+    #[test]
+    fn test_kbsr_writes_only_change_interrupt_enable() {
+        let mut console = BufferedConsole::new();
+        let mut keyboard = Keyboard::new();
+        keyboard.write(MR_KBSR, 0xFFFF, &mut console).unwrap();
+        assert_eq!(keyboard.peek(MR_KBSR), KBSR_INTERRUPT_ENABLE);
+        assert_eq!(keyboard.interrupt_request(&mut console), None);
+
+        console.push_input("k");
+        assert_eq!(
+            keyboard.read(MR_KBSR, &mut console).unwrap(),
+            KBSR_READY | KBSR_INTERRUPT_ENABLE
+        );
+        // Clearing the register cannot drop the pending key
+        keyboard.write(MR_KBSR, 0, &mut console).unwrap();
+        assert_eq!(keyboard.peek(MR_KBSR), KBSR_READY);
+        assert_eq!(
+            keyboard.read(MR_KBDR, &mut console).unwrap(),
+            u16::from(b'k')
+        );
+        assert_eq!(keyboard.peek(MR_KBSR), 0);
+    }
+
+    #[test]
+    fn test_timer_counts_instructions() {
+        let mut console = BufferedConsole::new();
+        let mut timer = Timer::with_interrupt(0x90, 6);
+        timer.write(MR_TMI, 3, &mut console).unwrap();
+        timer
+            .write(MR_TMR, TMR_INTERRUPT_ENABLE, &mut console)
+            .unwrap();
+
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.interrupt_request(&mut console), None);
+        timer.tick();
+        assert_eq!(
+            timer.interrupt_request(&mut console),
+            Some(InterruptRequest {
+                vector: 0x90,
+                priority: 6
+            })
+        );
+
+        assert_eq!(
+            timer.read(MR_TMR, &mut console).unwrap(),
+            TMR_EXPIRED | TMR_INTERRUPT_ENABLE
+        );
+        assert_eq!(
+            timer.read(MR_TMR, &mut console).unwrap(),
+            TMR_INTERRUPT_ENABLE
+        );
+        assert_eq!(timer.interrupt_request(&mut console), None);
+    }
+
+    #[test]
+    fn test_timer_wall_clock() {
+        let mut console = BufferedConsole::new();
+        let mut timer = Timer::new();
+        timer.write(MR_TMI, 1, &mut console).unwrap();
+        timer.write(MR_TMR, TMR_WALL_CLOCK, &mut console).unwrap();
+
+        std::thread::sleep(Duration::from_millis(5));
+        timer.tick();
+        assert_eq!(timer.peek(MR_TMR), TMR_EXPIRED | TMR_WALL_CLOCK);
+        // Expired, but interrupts are disabled
+        assert_eq!(timer.interrupt_request(&mut console), None);
+    }
+}
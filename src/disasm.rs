@@ -0,0 +1,234 @@
+use crate::opcode::{Opcode, Trap};
+use std::fmt;
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_opcode(f, self, None)
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Trap::GetC => "GETC",
+            Trap::Out => "OUT",
+            Trap::Puts => "PUTS",
+            Trap::In => "IN",
+            Trap::Putsp => "PUTSP",
+            Trap::Halt => "HALT",
+            Trap::Unknown(vector) => return write!(f, "TRAP x{:02X}", vector),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Displays an opcode located at `address`, printing PC-relative operands as
+/// the absolute address they refer to.
+pub struct AtAddress<'a> {
+    opcode: &'a Opcode,
+    address: u16,
+}
+
+impl Opcode {
+    pub fn at(&self, address: u16) -> AtAddress<'_> {
+        AtAddress {
+            opcode: self,
+            address,
+        }
+    }
+}
+
+impl fmt::Display for AtAddress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_opcode(f, self.opcode, Some(self.address))
+    }
+}
+
+fn write_opcode(f: &mut fmt::Formatter<'_>, opcode: &Opcode, address: Option<u16>) -> fmt::Result {
+    // Targets are relative to the incremented PC
+    let target = |offset: u16| match address {
+        Some(address) => format!("x{:04X}", address.wrapping_add(1).wrapping_add(offset)),
+        None => format!("#{}", signed(offset)),
+    };
+
+    match *opcode {
+        Opcode::OP_BR { n, z, p, offset } => {
+            // With no flags the word is just the 9-bit offset, usually data
+            if !(n || z || p) {
+                return match offset & 0x01FF {
+                    0 => write!(f, "NOP"),
+                    word => write!(f, ".FILL x{:04X}", word),
+                };
+            }
+            let flags: String = [(n, 'n'), (z, 'z'), (p, 'p')]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, flag)| *flag)
+                .collect();
+            write!(f, "BR{} {}", flags, target(offset))
+        }
+        Opcode::OP_ADD_REG { dr, sr1, sr2 } => write!(f, "ADD R{}, R{}, R{}", dr, sr1, sr2),
+        Opcode::OP_ADD_IMM { dr, sr1, imm5 } => {
+            write!(f, "ADD R{}, R{}, #{}", dr, sr1, signed(imm5))
+        }
+        Opcode::OP_LD { dr, offset } => write!(f, "LD R{}, {}", dr, target(offset)),
+        Opcode::OP_ST { sr, offset } => write!(f, "ST R{}, {}", sr, target(offset)),
+        Opcode::OP_JSR { offset } => write!(f, "JSR {}", target(offset)),
+        Opcode::OP_JSRR { base_r } => write!(f, "JSRR R{}", base_r),
+        Opcode::OP_AND_REG { dr, sr1, sr2 } => write!(f, "AND R{}, R{}, R{}", dr, sr1, sr2),
+        Opcode::OP_AND_IMM { dr, sr1, imm5 } => {
+            write!(f, "AND R{}, R{}, #{}", dr, sr1, signed(imm5))
+        }
+        Opcode::OP_LDR { dr, base_r, offset } => {
+            write!(f, "LDR R{}, R{}, #{}", dr, base_r, signed(offset))
+        }
+        Opcode::OP_STR { sr, base_r, offset } => {
+            write!(f, "STR R{}, R{}, #{}", sr, base_r, signed(offset))
+        }
+        Opcode::OP_RTI => write!(f, "RTI"),
+        Opcode::OP_NOT { dr, sr } => write!(f, "NOT R{}, R{}", dr, sr),
+        Opcode::OP_LDI { dr, offset } => write!(f, "LDI R{}, {}", dr, target(offset)),
+        Opcode::OP_STI { sr, offset } => write!(f, "STI R{}, {}", sr, target(offset)),
+        Opcode::OP_JMP { base_r } => write!(f, "JMP R{}", base_r),
+        Opcode::OP_RET => write!(f, "RET"),
+        Opcode::OP_RES => write!(f, "RES"),
+        Opcode::OP_LEA { dr, offset } => write!(f, "LEA R{}, {}", dr, target(offset)),
+        Opcode::OP_TRAP { trapvec } => write!(f, "{}", trapvec),
+    }
+}
+
+fn signed(value: u16) -> i16 {
+    i16::from_ne_bytes(value.to_ne_bytes())
+}
+
+/// One row of a disassembly listing.
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub word: u16,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "x{:04X}  x{:04X}  {}",
+            self.address, self.word, self.text
+        )
+    }
+}
+
+/// Disassembles an origin-prefixed program. Words that do not decode are shown
+/// as `.FILL`. With `resolve`, PC-relative operands are shown as addresses.
+pub fn disassemble(program: &[u16], resolve: bool) -> Vec<Line> {
+    let Some((&origin, words)) = program.split_first() else {
+        return Vec::new();
+    };
+
+    let mut address = origin;
+    let mut lines = Vec::new();
+    for &word in words {
+        let text = match Opcode::from(word) {
+            Ok(opcode) if resolve => opcode.at(address).to_string(),
+            Ok(opcode) => opcode.to_string(),
+            Err(_) => format!(".FILL x{:04X}", word),
+        };
+        lines.push(Line {
+            address,
+            word,
+            text,
+        });
+        address = address.wrapping_add(1);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_display() {
+        let opcode = Opcode::OP_ADD_IMM {
+            dr: 1,
+            sr1: 2,
+            imm5: 0xFFFD,
+        };
+        assert_eq!(opcode.to_string(), "ADD R1, R2, #-3");
+
+        let opcode = Opcode::OP_BR {
+            n: true,
+            z: true,
+            p: false,
+            offset: 0x000F,
+        };
+        assert_eq!(opcode.to_string(), "BRnz #15");
+        assert_eq!(opcode.at(0x3000).to_string(), "BRnz x3010");
+
+        let opcode = Opcode::OP_LDR {
+            dr: 0,
+            base_r: 6,
+            offset: 0xFFFF,
+        };
+        assert_eq!(opcode.to_string(), "LDR R0, R6, #-1");
+        assert_eq!(
+            Opcode::OP_TRAP {
+                trapvec: Trap::Puts
+            }
+            .to_string(),
+            "PUTS"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_round_trips_through_assembler() {
+        let source = "
+            .ORIG x3000
+    LOOP    ADD R1, R1, #-1
+            BRp LOOP
+            LEA R0, LOOP
+            NOT R2, R3
+            JSR LOOP
+            RET
+            .END
+        ";
+        let program = assemble(source).unwrap();
+        let listing = disassemble(&program.to_words(), true);
+        let text: Vec<&str> = listing.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "ADD R1, R1, #-1",
+                "BRp x3000",
+                "LEA R0, x3000",
+                "NOT R2, R3",
+                "JSR x3000",
+                "RET"
+            ]
+        );
+        assert_eq!(
+            listing.first().unwrap().to_string(),
+            "x3000  x127F  ADD R1, R1, #-1"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_unknown_trap() {
+        let listing = disassemble(&[0x3000, 0xF0FF], false);
+        assert_eq!(listing.first().unwrap().text, "TRAP xFF");
+    }
+
+    #[test]
+    fn test_disassemble_data_words() {
+        let listing = disassemble(&[0x3000, 0x0000, 0x0005, 0x0100, 0x01FF], true);
+        let text: Vec<&str> = listing.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec!["NOP", ".FILL x0005", ".FILL x0100", ".FILL x01FF"]
+        );
+    }
+}
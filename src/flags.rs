@@ -8,9 +8,14 @@ pub enum ConditionFlags {
 impl From<ConditionFlags> for u16 {
     fn from(val: ConditionFlags) -> Self {
         match val {
-            ConditionFlags::POS => 0,
+            ConditionFlags::POS => 1,
             ConditionFlags::ZRO => 2,
             ConditionFlags::NEG => 4,
         }
     }
 }
+
+/* Processor Status Register */
+pub const PSR_USER_MODE: u16 = 1 << 15; /* clear in supervisor mode */
+pub const PSR_PRIORITY: u16 = 0b0111_0000_0000; /* priority level in bits [10:8] */
+pub const PSR_COND: u16 = 0b0111; /* N, Z and P in bits [2:0] */
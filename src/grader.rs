@@ -0,0 +1,498 @@
+use crate::harness::{self, CaseOutcome, CaseReport, HarnessError, Spec};
+use crate::trace::json_escape;
+use crate::vm::words_from_obj;
+use std::fmt::Write as _;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// A student's program: either an `.obj` file used for every spec, or a
+/// directory holding each spec's `program`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submission {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Submission {
+    /// Names the submission after its file stem or directory.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        Self { name, path }
+    }
+
+    /// Names each submission after its file stem, prefixed with as many of
+    /// its parent directories as it takes to tell it apart from the others,
+    /// so a/lab1.obj and b/lab1.obj become `a/lab1` and `b/lab1`.
+    pub fn all(paths: &[impl AsRef<Path>]) -> Result<Vec<Self>, HarnessError> {
+        let parts: Vec<Vec<String>> = paths.iter().map(|path| name_parts(path.as_ref())).collect();
+        let mut depths = vec![1; parts.len()];
+        loop {
+            let names: Vec<String> = parts
+                .iter()
+                .zip(&depths)
+                .map(|(parts, &depth)| {
+                    parts
+                        .get(parts.len().saturating_sub(depth)..)
+                        .unwrap_or_default()
+                        .join("/")
+                })
+                .collect();
+            let mut lengthened = false;
+            for ((name, depth), parts) in names.iter().zip(depths.iter_mut()).zip(&parts) {
+                let clashes = names.iter().filter(|other| *other == name).count() > 1;
+                if clashes && *depth < parts.len() {
+                    *depth = depth.saturating_add(1);
+                    lengthened = true;
+                }
+            }
+            if lengthened {
+                continue;
+            }
+
+            if let Some(name) = names
+                .iter()
+                .find(|name| names.iter().filter(|other| other == name).count() > 1)
+            {
+                return Err(HarnessError::DuplicateSubmission(name.clone()));
+            }
+            return Ok(paths
+                .iter()
+                .zip(names)
+                .map(|(path, name)| Self {
+                    name,
+                    path: path.as_ref().to_path_buf(),
+                })
+                .collect());
+        }
+    }
+
+    fn program(&self, spec: &Spec) -> PathBuf {
+        if self.path.is_dir() {
+            self.path.join(&spec.program)
+        } else {
+            self.path.clone()
+        }
+    }
+}
+
+// The directories leading to a submission, ending with its file stem
+fn name_parts(path: &Path) -> Vec<String> {
+    let mut parts: Vec<String> = path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    parts.push(Submission::new(path).name);
+    parts
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradedCase {
+    pub spec: String,
+    pub points: u32,
+    pub report: CaseReport,
+}
+
+impl GradedCase {
+    pub fn earned(&self) -> u32 {
+        if self.report.passed() {
+            self.points
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmissionReport {
+    pub name: String,
+    pub cases: Vec<GradedCase>,
+}
+
+impl SubmissionReport {
+    pub fn score(&self) -> u32 {
+        self.cases
+            .iter()
+            .fold(0, |score, case| score.saturating_add(case.earned()))
+    }
+
+    pub fn max_score(&self) -> u32 {
+        self.cases
+            .iter()
+            .fold(0, |score, case| score.saturating_add(case.points))
+    }
+
+    pub fn to_json(&self) -> String {
+        let cases: Vec<String> = self.cases.iter().map(case_json).collect();
+        format!(
+            r#"{{"submission":"{}","score":{},"max_score":{},"cases":[{}]}}"#,
+            json_escape(&self.name),
+            self.score(),
+            self.max_score(),
+            cases.join(",")
+        )
+    }
+}
+
+fn case_json(case: &GradedCase) -> String {
+    let report = &case.report;
+    let error = match report.outcome.error() {
+        Some(error) => format!("\"{}\"", json_escape(error)),
+        None => "null".to_string(),
+    };
+    let failures: Vec<String> = report
+        .failures
+        .iter()
+        .map(|failure| format!("\"{}\"", json_escape(&failure.to_string())))
+        .collect();
+    format!(
+        r#"{{"spec":"{}","case":"{}","points":{},"earned":{},"outcome":"{}","error":{},"instructions":{},"output":"{}","failures":[{}]}}"#,
+        json_escape(&case.spec),
+        json_escape(&report.name),
+        case.points,
+        case.earned(),
+        report.outcome.kind(),
+        error,
+        report.instructions,
+        json_escape(&report.output),
+        failures.join(",")
+    )
+}
+
+/// Grades submissions against a fixed set of specs, several at a time. Every
+/// case runs on its own `CPU`.
+pub struct Grader {
+    specs: Vec<(String, Spec)>,
+    jobs: usize,
+    max_steps: Option<u64>,
+}
+
+impl Grader {
+    /// Takes each spec with the name it is reported under.
+    pub fn new(specs: Vec<(String, Spec)>) -> Self {
+        Self {
+            specs,
+            jobs: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            max_steps: None,
+        }
+    }
+
+    /// Loads spec files, naming each after its file stem.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self, HarnessError> {
+        let specs = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let name = path.file_stem().map_or_else(
+                    || path.display().to_string(),
+                    |stem| stem.to_string_lossy().into_owned(),
+                );
+                harness::load_spec(path).map(|spec| (name, spec))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(specs))
+    }
+
+    /// Number of submissions graded in parallel.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+
+    /// Instruction budget for cases whose spec sets none.
+    pub fn set_max_steps(&mut self, max_steps: Option<u64>) {
+        self.max_steps = max_steps;
+    }
+
+    pub fn grade(&self, submission: &Submission) -> SubmissionReport {
+        let mut cases = Vec::new();
+        for (name, spec) in &self.specs {
+            let path = submission.program(spec);
+            let program = match fs::read(&path) {
+                Ok(bytes) => words_from_obj(&bytes),
+                Err(err) => {
+                    let message = format!("Problem reading {}: {}", path.display(), err);
+                    cases.extend(Self::not_run(name, spec, &message));
+                    continue;
+                }
+            };
+            let max_steps = spec.max_steps.or(self.max_steps);
+            for case in &spec.cases {
+                let report = harness::run_case(case, &program, max_steps)
+                    .unwrap_or_else(|err| not_run_report(&case.name, &err.to_string()));
+                cases.push(GradedCase {
+                    spec: name.clone(),
+                    points: case.points,
+                    report,
+                });
+            }
+        }
+
+        SubmissionReport {
+            name: submission.name.clone(),
+            cases,
+        }
+    }
+
+    /// Grades every submission, returning the reports in the same order.
+    pub fn grade_all(&self, submissions: &[Submission]) -> Vec<SubmissionReport> {
+        let next = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.jobs.min(submissions.len()))
+                .map(|_| {
+                    let sender = sender.clone();
+                    let next = &next;
+                    scope.spawn(move || loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(submission) = submissions.get(index) else {
+                            break;
+                        };
+                        if sender.send((index, self.grade(submission))).is_err() {
+                            break;
+                        }
+                    })
+                })
+                .collect();
+            // A worker that panicked only loses the submission it was on
+            for worker in workers {
+                let _ = worker.join();
+            }
+        });
+        drop(sender);
+
+        let mut reports: Vec<Option<SubmissionReport>> = vec![None; submissions.len()];
+        for (index, report) in receiver {
+            if let Some(slot) = reports.get_mut(index) {
+                *slot = Some(report);
+            }
+        }
+        reports
+            .into_iter()
+            .zip(submissions)
+            .map(|(report, submission)| {
+                report.unwrap_or_else(|| SubmissionReport {
+                    name: submission.name.clone(),
+                    cases: self
+                        .specs
+                        .iter()
+                        .flat_map(|(name, spec)| Self::not_run(name, spec, "Grading crashed"))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// One row per submission with its total and the points earned on every
+    /// case, headed by `spec/case` column names.
+    pub fn summary_csv(&self, reports: &[SubmissionReport]) -> String {
+        let mut header = vec![
+            "submission".to_string(),
+            "score".to_string(),
+            "max_score".to_string(),
+        ];
+        for (name, spec) in &self.specs {
+            header.extend(
+                spec.cases
+                    .iter()
+                    .map(|case| format!("{}/{}", name, case.name)),
+            );
+        }
+        let mut csv = csv_row(&header);
+        for report in reports {
+            let mut row = vec![
+                report.name.clone(),
+                report.score().to_string(),
+                report.max_score().to_string(),
+            ];
+            row.extend(report.cases.iter().map(|case| case.earned().to_string()));
+            csv.push_str(&csv_row(&row));
+        }
+        csv
+    }
+
+    fn not_run(name: &str, spec: &Spec, message: &str) -> Vec<GradedCase> {
+        spec.cases
+            .iter()
+            .map(|case| GradedCase {
+                spec: name.to_string(),
+                points: case.points,
+                report: not_run_report(&case.name, message),
+            })
+            .collect()
+    }
+}
+
+fn not_run_report(name: &str, message: &str) -> CaseReport {
+    CaseReport {
+        name: name.to_string(),
+        outcome: CaseOutcome::LoadFailed(message.to_string()),
+        output: String::new(),
+        instructions: 0,
+        failures: Vec::new(),
+    }
+}
+
+fn csv_row(fields: &[String]) -> String {
+    let mut row = String::new();
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            row.push(',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            let _ = write!(row, "\"{}\"", field.replace('"', "\"\""));
+        } else {
+            row.push_str(field);
+        }
+    }
+    row.push('\n');
+    row
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const SPEC: &str = r#"
+        program = "echo.obj"
+        max_steps = 100
+
+        [[case]]
+        name = "echoes a"
+        input = "a"
+        output = "a"
+        points = 2
+
+        [[case]]
+        name = "echoes b"
+        input = "b"
+        output = "b"
+    "#;
+
+    fn write_submission(dir: &Path, name: &str, source: &str) -> Submission {
+        let path = dir.join(format!("{}.obj", name));
+        fs::write(&path, assemble(source).unwrap().to_obj_bytes()).unwrap();
+        Submission::new(path)
+    }
+
+    #[test]
+    fn test_grade_all() {
+        let dir = std::env::temp_dir().join(format!("lc3-grader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let submissions = vec![
+            write_submission(&dir, "good", ".ORIG x3000\nGETC\nOUT\nHALT\n.END"),
+            write_submission(
+                &dir,
+                "only_a",
+                ".ORIG x3000\nGETC\nLD R0, A\nOUT\nHALT\nA .FILL x61\n.END",
+            ),
+            write_submission(&dir, "loops", ".ORIG x3000\nLOOP BR LOOP\n.END"),
+            write_submission(&dir, "illegal", ".ORIG x3000\n.FILL xD000\n.END"),
+            Submission::new(dir.join("missing.obj")),
+        ];
+        let mut grader = Grader::new(vec![("echo".to_string(), toml::from_str(SPEC).unwrap())]);
+        grader.set_jobs(3);
+
+        let reports = grader.grade_all(&submissions);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let scores: Vec<(&str, u32)> = reports
+            .iter()
+            .map(|report| (report.name.as_str(), report.score()))
+            .collect();
+        assert_eq!(
+            scores,
+            vec![
+                ("good", 3),
+                ("only_a", 2),
+                ("loops", 0),
+                ("illegal", 0),
+                ("missing", 0)
+            ]
+        );
+        let kinds: Vec<&str> = reports
+            .iter()
+            .filter_map(|report| report.cases.first())
+            .map(|case| case.report.outcome.kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "halted",
+                "halted",
+                "budget_exhausted",
+                "crashed",
+                "load_failed"
+            ]
+        );
+        assert!(reports.iter().all(|report| report.max_score() == 3));
+
+        assert_eq!(
+            grader.summary_csv(reports.get(..2).unwrap()),
+            "submission,score,max_score,echo/echoes a,echo/echoes b\ngood,3,3,2,1\nonly_a,2,3,2,0\n"
+        );
+    }
+
+    #[test]
+    fn test_submission_names_are_unique() {
+        let submissions = Submission::all(&[
+            "a/lab1.obj",
+            "b/lab1.obj",
+            "c/lab2.obj",
+            "/x/a/lab3.obj",
+            "y/a/lab3",
+        ])
+        .unwrap();
+        let names: Vec<&str> = submissions
+            .iter()
+            .map(|submission| submission.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["a/lab1", "b/lab1", "lab2", "x/a/lab3", "y/a/lab3"]
+        );
+
+        assert!(matches!(
+            Submission::all(&["a/lab1.obj", "a/lab1"]),
+            Err(HarnessError::DuplicateSubmission(name)) if name == "a/lab1"
+        ));
+    }
+
+    #[test]
+    fn test_report_json() {
+        let grader = Grader::new(vec![("echo".to_string(), toml::from_str(SPEC).unwrap())]);
+        let report = SubmissionReport {
+            name: "bob".to_string(),
+            cases: Grader::not_run(
+                "echo",
+                grader.specs.first().map(|(_, spec)| spec).unwrap(),
+                "gone",
+            ),
+        };
+        assert_eq!(
+            report.to_json(),
+            concat!(
+                r#"{"submission":"bob","score":0,"max_score":3,"cases":["#,
+                r#"{"spec":"echo","case":"echoes a","points":2,"earned":0,"outcome":"load_failed","error":"gone","instructions":0,"output":"","failures":[]},"#,
+                r#"{"spec":"echo","case":"echoes b","points":1,"earned":0,"outcome":"load_failed","error":"gone","instructions":0,"output":"","failures":[]}]}"#
+            )
+        );
+        assert_eq!(
+            csv_row(&["a,b".to_string(), "say \"hi\"".to_string()]),
+            "\"a,b\",\"say \"\"hi\"\"\"\n"
+        );
+    }
+}
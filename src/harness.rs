@@ -0,0 +1,484 @@
+use crate::console::BufferedConsole;
+use crate::cpu::{RunOutcome, CPU};
+use crate::vm::words_from_obj;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Wall-clock limit for cases without `max_steps`, so a looping program
+/// cannot hang the run.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum HarnessError {
+    #[error("Problem reading {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid test spec: {0}")]
+    Spec(#[from] toml::de::Error),
+    #[error("Unknown register '{0}' in case '{1}'")]
+    UnknownRegister(String, String),
+    #[error("More than one submission is named '{0}'")]
+    DuplicateSubmission(String),
+}
+
+/// A test spec: one program and the cases to run it against.
+///
+/// ```toml
+/// program = "echo.obj"     # relative to the spec file
+///
+/// [[case]]
+/// name = "echoes a key"
+/// input = "a"
+/// output = "a"
+/// registers = { R0 = 0x61 }
+/// memory = [{ address = 0x4000, values = [1, 2] }]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    pub program: PathBuf,
+    /// Default instruction budget for every case.
+    pub max_steps: Option<u64>,
+    #[serde(rename = "case")]
+    pub cases: Vec<Case>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub name: String,
+    /// Keys the program reads, in order.
+    #[serde(default)]
+    pub input: String,
+    /// Everything the program writes, when given.
+    pub output: Option<String>,
+    /// Final values of R0-R7 or PC.
+    #[serde(default)]
+    pub registers: BTreeMap<String, u16>,
+    #[serde(default)]
+    pub memory: Vec<MemoryExpectation>,
+    pub max_steps: Option<u64>,
+    /// What the case is worth when graded.
+    #[serde(default = "default_points")]
+    pub points: u32,
+}
+
+fn default_points() -> u32 {
+    1
+}
+
+/// Expected contents of `values.len()` words starting at `address`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryExpectation {
+    pub address: u16,
+    pub values: Vec<u16>,
+}
+
+/// How the program's run ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaseOutcome {
+    Halted,
+    BudgetExhausted,
+    TimedOut,
+    /// Stopped by a `CPUError`, with its message.
+    Crashed(String),
+    /// The program could not be loaded.
+    LoadFailed(String),
+}
+
+impl CaseOutcome {
+    /// A short machine-readable name.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CaseOutcome::Halted => "halted",
+            CaseOutcome::BudgetExhausted => "budget_exhausted",
+            CaseOutcome::TimedOut => "timed_out",
+            CaseOutcome::Crashed(_) => "crashed",
+            CaseOutcome::LoadFailed(_) => "load_failed",
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            CaseOutcome::Crashed(message) | CaseOutcome::LoadFailed(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CaseOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaseOutcome::Halted => write!(f, "halted"),
+            CaseOutcome::BudgetExhausted => write!(f, "did not halt within the step limit"),
+            CaseOutcome::TimedOut => write!(f, "timed out"),
+            CaseOutcome::Crashed(message) | CaseOutcome::LoadFailed(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    Output {
+        expected: String,
+        actual: String,
+    },
+    Register {
+        name: String,
+        expected: u16,
+        actual: u16,
+    },
+    Memory {
+        address: u16,
+        expected: u16,
+        actual: u16,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Output { expected, actual } => {
+                writeln!(f, "output differs (-expected +actual):")?;
+                write!(f, "{}", diff_lines(expected, actual))
+            }
+            Failure::Register {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: expected x{:04X}, got x{:04X}",
+                name, expected, actual
+            ),
+            Failure::Memory {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "memory x{:04X}: expected x{:04X}, got x{:04X}",
+                address, expected, actual
+            ),
+        }
+    }
+}
+
+/// The result of one case. It passed when the program halted and every
+/// expectation held.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseReport {
+    pub name: String,
+    pub outcome: CaseOutcome,
+    pub output: String,
+    pub instructions: u64,
+    pub failures: Vec<Failure>,
+}
+
+impl CaseReport {
+    pub fn passed(&self) -> bool {
+        self.outcome == CaseOutcome::Halted && self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for CaseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.passed() { "PASS" } else { "FAIL" };
+        write!(f, "{} {}", status, self.name)?;
+        if self.outcome != CaseOutcome::Halted {
+            write!(f, "\n    {}", self.outcome)?;
+        }
+        for failure in &self.failures {
+            for line in failure.to_string().lines() {
+                write!(f, "\n    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn load_spec(path: impl AsRef<Path>) -> Result<Spec, HarnessError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|source| HarnessError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(toml::from_str(&text)?)
+}
+
+/// Loads a spec and runs all its cases. The program path is resolved
+/// relative to the spec file.
+pub fn run_spec_file(path: impl AsRef<Path>) -> Result<Vec<CaseReport>, HarnessError> {
+    let path = path.as_ref();
+    let spec = load_spec(path)?;
+    let program_path = path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&spec.program);
+    let bytes = fs::read(&program_path).map_err(|source| HarnessError::Io {
+        path: program_path,
+        source,
+    })?;
+
+    run_spec(&spec, &words_from_obj(&bytes))
+}
+
+/// Runs every case of `spec` against an origin-prefixed program image.
+pub fn run_spec(spec: &Spec, program: &[u16]) -> Result<Vec<CaseReport>, HarnessError> {
+    spec.cases
+        .iter()
+        .map(|case| run_case(case, program, spec.max_steps))
+        .collect()
+}
+
+pub fn run_case(
+    case: &Case,
+    program: &[u16],
+    default_max_steps: Option<u64>,
+) -> Result<CaseReport, HarnessError> {
+    let registers = case
+        .registers
+        .iter()
+        .map(|(name, value)| {
+            register_index(name)
+                .map(|index| (name, index, *value))
+                .ok_or_else(|| HarnessError::UnknownRegister(name.clone(), case.name.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let console = BufferedConsole::with_input(&case.input);
+    let mut cpu = CPU::with_console(Box::new(console.clone()));
+    let mut failures = Vec::new();
+
+    let outcome = match cpu.memory.load_program(program) {
+        Err(err) => CaseOutcome::LoadFailed(err.to_string()),
+        Ok(()) => {
+            let result = match case.max_steps.or(default_max_steps) {
+                Some(max_steps) => cpu.run_for(max_steps),
+                None => {
+                    cpu.timeout = Some(DEFAULT_TIMEOUT);
+                    cpu.execute_program()
+                }
+            };
+            match result {
+                Ok(RunOutcome::Halted) => CaseOutcome::Halted,
+                Ok(RunOutcome::BudgetExhausted) => CaseOutcome::BudgetExhausted,
+                Ok(RunOutcome::TimedOut) => CaseOutcome::TimedOut,
+                Err(err) => CaseOutcome::Crashed(err.to_string()),
+            }
+        }
+    };
+
+    let output = console.output_string();
+    if let Some(expected) = &case.output {
+        if *expected != output {
+            failures.push(Failure::Output {
+                expected: expected.clone(),
+                actual: output.clone(),
+            });
+        }
+    }
+
+    for (name, index, expected) in registers {
+        let actual = cpu.get_register_value(index).unwrap_or_default();
+        if actual != expected {
+            failures.push(Failure::Register {
+                name: name.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+
+    for expectation in &case.memory {
+        let mut address = expectation.address;
+        for &expected in &expectation.values {
+            let actual = cpu.memory.peek(address);
+            if actual != expected {
+                failures.push(Failure::Memory {
+                    address,
+                    expected,
+                    actual,
+                });
+            }
+            address = address.wrapping_add(1);
+        }
+    }
+
+    Ok(CaseReport {
+        name: case.name.clone(),
+        outcome,
+        output,
+        instructions: cpu.instruction_count,
+        failures,
+    })
+}
+
+fn register_index(name: &str) -> Option<u16> {
+    match name.to_uppercase().as_str() {
+        "PC" => Some(8),
+        register => register
+            .strip_prefix('R')
+            .and_then(|index| index.parse().ok())
+            .filter(|index| *index < 8),
+    }
+}
+
+// A line-by-line comparison: equal lines are shown once, differing ones as a
+// `-` line and a `+` line.
+fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.split('\n').collect();
+    let actual: Vec<&str> = actual.split('\n').collect();
+    let mut diff = String::new();
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(left), Some(right)) if left == right => {
+                diff.push_str(&format!("  {:?}\n", left));
+            }
+            (left, right) => {
+                if let Some(left) = left {
+                    diff.push_str(&format!("- {:?}\n", left));
+                }
+                if let Some(right) = right {
+                    diff.push_str(&format!("+ {:?}\n", right));
+                }
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const ECHO: &str = r#"
+            .ORIG x3000
+            GETC
+            OUT
+            ST R0, SAVED
+            LEA R0, DONE
+            PUTS
+            HALT
+    SAVED   .BLKW 1
+    DONE    .STRINGZ "\ndone"
+            .END
+    "#;
+
+    fn spec(cases: &str) -> Spec {
+        toml::from_str(&format!("program = \"echo.obj\"\n{}", cases)).unwrap()
+    }
+
+    #[test]
+    fn test_passing_case() {
+        let spec = spec(
+            r#"
+            [[case]]
+            name = "echoes"
+            input = "a"
+            output = "a\ndone"
+            registers = { R0 = 0x3007, r7 = 0x3006 }
+            memory = [{ address = 0x3006, values = [0x61] }]
+            "#,
+        );
+        let program = assemble(ECHO).unwrap().to_words();
+        let reports = run_spec(&spec, &program).unwrap();
+
+        let report = reports.first().unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.instructions, 6);
+        assert_eq!(report.to_string(), "PASS echoes");
+    }
+
+    #[test]
+    fn test_failing_case_reports_diffs() {
+        let spec = spec(
+            r#"
+            [[case]]
+            name = "wrong"
+            input = "b"
+            output = "a\ndone"
+            registers = { R1 = 1 }
+            memory = [{ address = 0x3006, values = [0x61] }]
+            "#,
+        );
+        let program = assemble(ECHO).unwrap().to_words();
+        let report = run_spec(&spec, &program).unwrap().remove(0);
+
+        assert_eq!(
+            report.failures,
+            vec![
+                Failure::Output {
+                    expected: "a\ndone".to_string(),
+                    actual: "b\ndone".to_string()
+                },
+                Failure::Register {
+                    name: "R1".to_string(),
+                    expected: 1,
+                    actual: 0
+                },
+                Failure::Memory {
+                    address: 0x3006,
+                    expected: 0x61,
+                    actual: 0x62
+                },
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "FAIL wrong\n    output differs (-expected +actual):\n    - \"a\"\n    + \"b\"\n      \"done\"\n    R1: expected x0001, got x0000\n    memory x3006: expected x0061, got x0062"
+        );
+    }
+
+    #[test]
+    fn test_run_failures() {
+        let spec = spec(
+            r#"
+            max_steps = 3
+
+            [[case]]
+            name = "no input"
+
+            [[case]]
+            name = "too slow"
+            input = "a"
+            max_steps = 2
+            "#,
+        );
+        let program = assemble(ECHO).unwrap().to_words();
+        let reports = run_spec(&spec, &program).unwrap();
+
+        let outcomes: Vec<&CaseOutcome> = reports.iter().map(|report| &report.outcome).collect();
+        assert!(
+            matches!(outcomes.first(), Some(CaseOutcome::Crashed(message)) if message.contains("GetC"))
+        );
+        assert_eq!(outcomes.get(1), Some(&&CaseOutcome::BudgetExhausted));
+        assert!(reports.iter().all(|report| !report.passed()));
+        assert_eq!(
+            reports.get(1).unwrap().to_string(),
+            "FAIL too slow\n    did not halt within the step limit"
+        );
+    }
+
+    #[test]
+    fn test_unknown_register() {
+        let spec = spec("[[case]]\nname = \"bad\"\nregisters = { R9 = 1 }");
+        let program = assemble(ECHO).unwrap().to_words();
+        assert!(matches!(
+            run_spec(&spec, &program),
+            Err(HarnessError::UnknownRegister(name, _)) if name == "R9"
+        ));
+    }
+}
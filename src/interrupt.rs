@@ -0,0 +1,11 @@
+/// A device asking the CPU to run the service routine behind `vector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptRequest {
+    pub vector: u16,
+    pub priority: u16,
+}
+
+pub const KEYBOARD_VECTOR: u16 = 0x80;
+pub const KEYBOARD_PRIORITY: u16 = 4;
+pub const TIMER_VECTOR: u16 = 0x81;
+pub const TIMER_PRIORITY: u16 = 5;
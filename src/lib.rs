@@ -0,0 +1,22 @@
+pub mod assembler;
+pub mod console;
+pub mod cpu;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod flags;
+pub mod grader;
+pub mod harness;
+pub mod interrupt;
+pub mod memory;
+pub mod opcode;
+pub mod os;
+pub mod replay;
+pub mod snapshot;
+pub mod step;
+pub mod trace;
+pub mod undo;
+pub mod vm;
+
+pub use cpu::{ExceptionMode, RunOutcome, TrapMode};
+pub use vm::{Vm, VmError};
@@ -1,62 +1,460 @@
-use cpu::CPU;
+use lc3_vm_rust::{
+    assembler,
+    console::{BufferedConsole, Console, StdioConsole},
+    debugger::Debugger,
+    disasm,
+    grader::{Grader, Submission},
+    harness,
+    replay::{self, RecordingConsole, ReplayConsole},
+    trace::{TraceFormat, Tracer},
+    vm::words_from_obj,
+    ExceptionMode, RunOutcome, TrapMode, Vm,
+};
 use std::{
-    env,
-    fs::{self},
+    env, fs,
+    io::{self, BufReader, Write},
+    path::Path,
+    process,
+    time::Duration,
 };
-use termios::*;
+use terminal::TerminalGuard;
 
-mod cpu;
-mod flags;
-mod memory;
-mod opcode;
+mod terminal;
 
 fn main() {
-    // Configure Termios
-    let stdin = 0;
-    let Ok(mut termios) = Termios::from_fd(stdin) else {
-        eprintln!("Failed to initialize terminal");
-        return;
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("assemble") => assemble(args.get(2..).unwrap_or_default()),
+        Some("disasm") => disassemble(args.get(2..).unwrap_or_default()),
+        Some("debug") => debug(args.get(2..).unwrap_or_default()),
+        Some("test") => test(args.get(2..).unwrap_or_default()),
+        Some("grade") => grade(args.get(2..).unwrap_or_default()),
+        Some(_) => process::exit(run(args.get(1..).unwrap_or_default())),
+        None => {
+            eprintln!("Failed to get the filename from args");
+            process::exit(EXIT_USAGE);
+        }
+    }
+}
+
+// Exit statuses of `run`, so scripts can tell why a program stopped
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_BUDGET_EXHAUSTED: i32 = 3;
+const EXIT_TIMED_OUT: i32 = 4;
+
+#[derive(Default)]
+struct RunOptions {
+    filename: String,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    exception_mode: Option<ExceptionMode>,
+    trap_mode: Option<TrapMode>,
+    os: bool,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+// <file.obj> [--max-steps <n>] [--timeout <seconds>] [--strict]
+//            [--trap-table] [--trap-exception] [--os]
+//            [--trace <file>] [--trace-format human|json]
+//            [--load-state <file>] [--save-state <file>] [--record <file>] [--replay <file>]
+// The program file is optional with --load-state.
+fn parse_run_options(args: &[String]) -> Result<RunOptions, String> {
+    let mut options = RunOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-steps" => {
+                let value = args.next().ok_or("--max-steps expects a number")?;
+                let steps = value
+                    .parse()
+                    .map_err(|_| format!("Invalid --max-steps value: {}", value))?;
+                options.max_steps = Some(steps);
+            }
+            "--timeout" => {
+                let value = args.next().ok_or("--timeout expects seconds")?;
+                let timeout = value
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or(format!("Invalid --timeout value: {}", value))?;
+                options.timeout = Some(timeout);
+            }
+            "--strict" => options.exception_mode = Some(ExceptionMode::Strict),
+            "--trap-table" => options.trap_mode = Some(TrapMode::VectorTable),
+            "--trap-exception" => options.trap_mode = Some(TrapMode::Exception),
+            "--os" => options.os = true,
+            "--trace" => {
+                let file = args.next().ok_or("--trace expects a file")?;
+                options.trace = Some(file.clone());
+            }
+            "--trace-format" => {
+                let value = args.next().ok_or("--trace-format expects human or json")?;
+                options.trace_format = match value.as_str() {
+                    "human" => TraceFormat::Human,
+                    "json" => TraceFormat::JsonLines,
+                    _ => return Err(format!("Invalid --trace-format value: {}", value)),
+                };
+            }
+            "--load-state" => {
+                let file = args.next().ok_or("--load-state expects a file")?;
+                options.load_state = Some(file.clone());
+            }
+            "--save-state" => {
+                let file = args.next().ok_or("--save-state expects a file")?;
+                options.save_state = Some(file.clone());
+            }
+            "--record" => {
+                let file = args.next().ok_or("--record expects a file")?;
+                options.record = Some(file.clone());
+            }
+            "--replay" => {
+                let file = args.next().ok_or("--replay expects a file")?;
+                options.replay = Some(file.clone());
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            filename => options.filename = filename.to_string(),
+        }
+    }
+
+    if options.filename.is_empty() && options.load_state.is_none() {
+        return Err("Failed to get the filename from args".to_string());
+    }
+    Ok(options)
+}
+
+// Returns the exit status; the terminal is restored before the process exits.
+fn run(args: &[String]) -> i32 {
+    let options = match parse_run_options(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return EXIT_USAGE;
+        }
+    };
+
+    // Restores the terminal when it goes out of scope
+    let _terminal = match TerminalGuard::raw_mode() {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Failed to initialize terminal, running headless: {}", e);
+            None
+        }
+    };
+
+    let console = match open_console(&options) {
+        Ok(console) => console,
+        Err(err) => {
+            eprintln!("{}", err);
+            return EXIT_USAGE;
+        }
+    };
+    let mut vm = Vm::with_console(console);
+    vm.set_timeout(options.timeout);
+    // The OS goes in first so the program can still overwrite any of it
+    if options.os {
+        if let Err(err) = vm.load_os() {
+            eprintln!("{}", err);
+            return EXIT_USAGE;
+        }
+    }
+    if !options.filename.is_empty() {
+        if let Err(err) = vm.load_obj_file(&options.filename) {
+            eprintln!("{}", err);
+            return EXIT_USAGE;
+        };
+    }
+    // The snapshot replaces the whole machine state, modes included
+    if let Some(state) = &options.load_state {
+        if let Err(err) = vm.load_state_file(state) {
+            eprintln!("{}: {}", state, err);
+            return EXIT_USAGE;
+        }
+    }
+    // Mode flags go last so they override both the OS and the snapshot
+    if let Some(mode) = options.exception_mode {
+        vm.set_exception_mode(mode);
+    }
+    if let Some(mode) = options.trap_mode {
+        vm.set_trap_mode(mode);
+    }
+    if let Some(trace) = &options.trace {
+        match fs::File::create(trace) {
+            Ok(file) => vm.set_tracer(Some(Tracer::new(
+                io::BufWriter::new(file),
+                options.trace_format,
+            ))),
+            Err(err) => {
+                eprintln!("Problem creating {}: {}", trace, err);
+                return EXIT_USAGE;
+            }
+        }
+    }
+    let outcome = match options.max_steps {
+        Some(max_steps) => vm.run_for(max_steps),
+        None => vm.run(),
+    };
+    let mut status = match outcome {
+        Ok(RunOutcome::Halted) => 0,
+        Ok(RunOutcome::BudgetExhausted) => {
+            eprintln!("\nExecution stopped: instruction budget exhausted");
+            EXIT_BUDGET_EXHAUSTED
+        }
+        Ok(RunOutcome::TimedOut) => {
+            eprintln!("\nExecution stopped: timed out");
+            EXIT_TIMED_OUT
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            EXIT_ERROR
+        }
     };
+    if let Some(state) = &options.save_state {
+        if let Err(err) = vm.save_state_file(state) {
+            eprintln!("Problem writing {}: {}", state, err);
+            if status == 0 {
+                status = EXIT_ERROR;
+            }
+        }
+    }
+    status
+}
 
-    termios.c_lflag &= !(ICANON | ECHO);
+// Replayed input comes first; with both flags, a replay can be re-recorded
+// and extended.
+fn open_console(options: &RunOptions) -> Result<Box<dyn Console>, String> {
+    let mut console: Box<dyn Console> = Box::new(StdioConsole::new());
+    if let Some(log) = &options.replay {
+        let events = fs::read_to_string(log)
+            .and_then(|text| replay::parse_log(&text))
+            .map_err(|err| format!("Problem reading {}: {}", log, err))?;
+        console = Box::new(ReplayConsole::new(console, events));
+    }
+    if let Some(log) = &options.record {
+        let file =
+            fs::File::create(log).map_err(|err| format!("Problem creating {}: {}", log, err))?;
+        console = Box::new(RecordingConsole::new(console, file));
+    }
+    Ok(console)
+}
 
-    if let Err(e) = tcsetattr(0, TCSANOW, &termios)
-        .inspect_err(|e| eprintln!("Failed to initialize terminal: {}", e))
-    {
-        println!("{}", e);
+// assemble <input.asm> [-o <output.obj>]
+fn assemble(args: &[String]) {
+    let Some(input) = args.first() else {
+        eprintln!("Usage: assemble <input.asm> [-o <output.obj>]");
         return;
+    };
+    let output = match args.get(1..) {
+        Some([flag, output]) if flag == "-o" => output.into(),
+        _ => Path::new(input).with_extension("obj"),
+    };
+
+    let source = match fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Problem reading the file: {}", err);
+            return;
+        }
+    };
+    let program = match assembler::assemble(&source) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("{}: {}", input, err);
+            return;
+        }
+    };
+    if let Err(err) = fs::write(&output, program.to_obj_bytes()) {
+        eprintln!("Problem writing {}: {}", output.display(), err);
     }
+}
 
-    let args: Vec<String> = env::args().collect();
-    let Some(filename) = args.get(1) else {
-        eprintln!("Failed to get the filename from args");
+// disasm <file.obj> [--resolve]
+fn disassemble(args: &[String]) {
+    let Some(input) = args.first() else {
+        eprintln!("Usage: disasm <file.obj> [--resolve]");
         return;
     };
+    let resolve = args.iter().any(|arg| arg == "--resolve");
 
-    let bytes = match load_obj(filename) {
+    let bytes = match fs::read(input) {
         Ok(bytes) => bytes,
-        Err(_) => return,
+        Err(err) => {
+            eprintln!("Problem reading the file: {}", err);
+            return;
+        }
+    };
+    let mut stdout = io::stdout().lock();
+    for line in disasm::disassemble(&words_from_obj(&bytes), resolve) {
+        if let Err(err) = writeln!(stdout, "{}", line) {
+            // The reader went away, e.g. `disasm x.obj | head`
+            if err.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("Problem writing the listing: {}", err);
+            }
+            return;
+        }
+    }
+}
+
+// debug <file.obj>
+fn debug(args: &[String]) {
+    let Some(filename) = args.first() else {
+        eprintln!("Usage: debug <file.obj>");
+        return;
     };
 
-    let mut cpu = CPU::new();
-    if let Err(err) = cpu.memory.load_program(&bytes) {
-        eprintln!("Error loading program: {}", err);
+    // The terminal stays in canonical mode so commands can be edited. The
+    // program's keys come from the `input` command, not from stdin, which the
+    // debugger reads commands from
+    let console = BufferedConsole::new();
+    let mut vm = Vm::with_console(console.clone());
+    if let Err(err) = vm.load_obj_file(filename) {
+        eprintln!("{}", err);
         return;
     };
-    if let Err(err) = cpu.execute_program() {
-        eprintln!("Error running program: {}", err);
+    let mut debugger = Debugger::with_console(vm, console);
+    if let Err(err) = debugger.run(BufReader::new(io::stdin()), io::stdout()) {
+        eprintln!("Debugger failed: {}", err);
     }
 }
 
-fn load_obj(filename: &str) -> Result<Vec<u16>, String> {
-    let data = fs::read(filename).map_err(|e| format!("Problem reading the file: {}", e))?;
-    let mut loaded_memory = Vec::new();
-    for two_bytes in data.chunks_exact(2) {
-        let first_byte = two_bytes.first().ok_or("Error obtaining the first byte")?;
-        let second_byte = two_bytes.get(1).ok_or("Error obtaining the second byte")?;
-        let joined_bytes = u16::from_be_bytes([*first_byte, *second_byte]);
-        loaded_memory.push(joined_bytes);
+// test <spec.toml>...
+fn test(args: &[String]) {
+    if args.is_empty() {
+        eprintln!("Usage: test <spec.toml>...");
+        return;
+    }
+
+    let mut passed = 0usize;
+    let mut failed = 0usize;
+    for spec in args {
+        let reports = match harness::run_spec_file(spec) {
+            Ok(reports) => reports,
+            Err(err) => {
+                eprintln!("{}: {}", spec, err);
+                failed = failed.saturating_add(1);
+                continue;
+            }
+        };
+        println!("{}", spec);
+        for report in reports {
+            println!("  {}", report.to_string().replace('\n', "\n  "));
+            if report.passed() {
+                passed = passed.saturating_add(1);
+            } else {
+                failed = failed.saturating_add(1);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
     }
+}
+
+#[derive(Default)]
+struct GradeOptions {
+    specs: Vec<String>,
+    submissions: Vec<String>,
+    out: Option<String>,
+    jobs: Option<usize>,
+    max_steps: Option<u64>,
+}
 
-    Ok(loaded_memory)
+// grade --spec <spec.toml>... [--out <dir>] [--jobs <n>] [--max-steps <n>] <submission>...
+fn parse_grade_options(args: &[String]) -> Result<GradeOptions, String> {
+    let mut options = GradeOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--spec" => {
+                let spec = args.next().ok_or("--spec expects a file")?;
+                options.specs.push(spec.clone());
+            }
+            "--out" => {
+                let out = args.next().ok_or("--out expects a directory")?;
+                options.out = Some(out.clone());
+            }
+            "--jobs" => {
+                let value = args.next().ok_or("--jobs expects a number")?;
+                let jobs = value
+                    .parse()
+                    .map_err(|_| format!("Invalid --jobs value: {}", value))?;
+                options.jobs = Some(jobs);
+            }
+            "--max-steps" => {
+                let value = args.next().ok_or("--max-steps expects a number")?;
+                let steps = value
+                    .parse()
+                    .map_err(|_| format!("Invalid --max-steps value: {}", value))?;
+                options.max_steps = Some(steps);
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            submission => options.submissions.push(submission.to_string()),
+        }
+    }
+
+    if options.specs.is_empty() || options.submissions.is_empty() {
+        return Err("Usage: grade --spec <spec.toml>... [--out <dir>] [--jobs <n>] [--max-steps <n>] <submission>...".to_string());
+    }
+    Ok(options)
+}
+
+fn grade(args: &[String]) {
+    let options = match parse_grade_options(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+
+    let mut grader = match Grader::load(&options.specs) {
+        Ok(grader) => grader,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    if let Some(jobs) = options.jobs {
+        grader.set_jobs(jobs);
+    }
+    grader.set_max_steps(options.max_steps);
+
+    let submissions = match Submission::all(&options.submissions) {
+        Ok(submissions) => submissions,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    let reports = grader.grade_all(&submissions);
+
+    let out = Path::new(options.out.as_deref().unwrap_or("grades"));
+    if let Err(err) = fs::create_dir_all(out) {
+        eprintln!("Problem creating {}: {}", out.display(), err);
+        return;
+    }
+    for report in &reports {
+        // Names that needed a parent directory to be unique get a subdirectory
+        let path = out.join(format!("{}.json", report.name));
+        if let Some(parent) = path.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                eprintln!("Problem creating {}: {}", parent.display(), err);
+            }
+        }
+        if let Err(err) = fs::write(&path, report.to_json()) {
+            eprintln!("Problem writing {}: {}", path.display(), err);
+        }
+        println!("{}: {}/{}", report.name, report.score(), report.max_score());
+    }
+    let summary = out.join("summary.csv");
+    if let Err(err) = fs::write(&summary, grader.summary_csv(&reports)) {
+        eprintln!("Problem writing {}: {}", summary.display(), err);
+    }
 }
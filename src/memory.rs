@@ -1,9 +1,15 @@
-use std::io::Read;
+use crate::console::{Console, StdioConsole};
+use crate::device::{
+    Device, Display, Keyboard, MachineControl, Timer, KBSR_READY, MCR_CLOCK_ENABLE, MR_DDR, MR_DSR,
+    MR_KBDR, MR_KBSR, MR_MCR, MR_TMI, MR_TMR,
+};
+use crate::interrupt::InterruptRequest;
+use crate::step::MemoryWrite;
+use crate::undo::MemoryJournal;
+use std::ops::RangeInclusive;
 use thiserror::Error;
 
 const MEMORY_SIZE: usize = 1 << 16;
-const MR_KBSR: u16 = 0xFE00; /* keyboard status */
-const MR_KBDR: u16 = 0xFE02; /* keyboard data */
 
 #[derive(Error, Debug)]
 pub enum MemoryError {
@@ -15,20 +21,244 @@ pub enum MemoryError {
     LoadProgram,
     #[error("Failed to read the keyboard")]
     Keyboard,
+    #[error("Failed to read the keyboard: {0}")]
+    KeyboardInput(String),
+    #[error("Failed to write to the display")]
+    Display,
+    #[error("Device range x{start:04X}-x{end:04X} overlaps a mapped device")]
+    DeviceOverlap { start: u16, end: u16 },
+    #[error("Failed to restore device state: {0}")]
+    DeviceState(String),
 }
 
+struct MappedDevice {
+    range: RangeInclusive<u16>,
+    device: Box<dyn Device>,
+}
+
+/// RAM plus the devices mapped in front of it. Accesses inside a device's
+/// range go to the device instead of `cells`.
 pub struct Memory {
     pub cells: [u16; MEMORY_SIZE],
+    console: Box<dyn Console>,
+    devices: Vec<MappedDevice>,
+    journal: Option<MemoryJournal>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
+        Self::with_console(Box::new(StdioConsole::new()))
+    }
+
+    /// Memory with the standard keyboard, display, timer and machine control devices.
+    pub fn with_console(console: Box<dyn Console>) -> Self {
+        let mut memory = Self::without_devices(console);
+        memory.devices = vec![
+            MappedDevice {
+                range: MR_KBSR..=MR_KBDR,
+                device: Box::new(Keyboard::new()),
+            },
+            MappedDevice {
+                range: MR_DSR..=MR_DDR,
+                device: Box::new(Display::new()),
+            },
+            MappedDevice {
+                range: MR_TMR..=MR_TMI,
+                device: Box::new(Timer::new()),
+            },
+            MappedDevice {
+                range: MR_MCR..=MR_MCR,
+                device: Box::new(MachineControl::new()),
+            },
+        ];
+        memory
+    }
+
+    /// Plain RAM: every address, device registers included, is a memory cell.
+    pub fn without_devices(console: Box<dyn Console>) -> Self {
         Self {
             cells: [0; MEMORY_SIZE],
+            console,
+            devices: Vec::new(),
+            journal: None,
+        }
+    }
+
+    pub fn console_mut(&mut self) -> &mut dyn Console {
+        self.console.as_mut()
+    }
+
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.console = console;
+    }
+
+    /// Routes accesses to `range` to `device`.
+    pub fn map_device(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: Box<dyn Device>,
+    ) -> Result<(), MemoryError> {
+        let overlaps = self.devices.iter().any(|mapped| {
+            range.start() <= mapped.range.end() && mapped.range.start() <= range.end()
+        });
+        if overlaps {
+            return Err(MemoryError::DeviceOverlap {
+                start: *range.start(),
+                end: *range.end(),
+            });
         }
+
+        self.devices.push(MappedDevice { range, device });
+        Ok(())
+    }
+
+    /// Removes the device mapped at `address`, so it can be replaced.
+    pub fn unmap_device(&mut self, address: u16) -> Option<Box<dyn Device>> {
+        let index = self
+            .devices
+            .iter()
+            .position(|mapped| mapped.range.contains(&address))?;
+        Some(self.devices.remove(index).device)
+    }
+
+    /// The saved state of every mapped device, with the range it is mapped at.
+    pub fn device_states(&self) -> Vec<(RangeInclusive<u16>, Vec<u16>)> {
+        self.devices
+            .iter()
+            .map(|mapped| (mapped.range.clone(), mapped.device.save_state()))
+            .collect()
+    }
+
+    /// Restores the state of the device mapped at exactly `range`.
+    pub fn load_device_state(
+        &mut self,
+        range: &RangeInclusive<u16>,
+        state: &[u16],
+    ) -> Result<(), MemoryError> {
+        let mapped = self
+            .devices
+            .iter_mut()
+            .find(|mapped| mapped.range == *range)
+            .ok_or_else(|| unmapped_range(range))?;
+        mapped.device.load_state(state)
+    }
+
+    /// Restores every device in `states` or none of them: each range must have
+    /// a device mapped at exactly it, and if a device rejects its state the
+    /// ones already restored are put back.
+    pub fn load_device_states(
+        &mut self,
+        states: &[(RangeInclusive<u16>, Vec<u16>)],
+    ) -> Result<(), MemoryError> {
+        if let Some((range, _)) = states
+            .iter()
+            .find(|(range, _)| !self.devices.iter().any(|mapped| mapped.range == *range))
+        {
+            return Err(unmapped_range(range));
+        }
+
+        let before = self.device_states();
+        for (range, state) in states {
+            if let Err(err) = self.load_device_state(range, state) {
+                for (range, state) in &before {
+                    self.load_device_state(range, state)?;
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts recording what is about to change: the old value of every write
+    /// and the state of each device before it is first accessed.
+    pub(crate) fn start_journal(&mut self) {
+        let devices = self
+            .devices
+            .iter()
+            .filter(|mapped| mapped.device.is_active())
+            .map(|mapped| (mapped.range.clone(), mapped.device.save_state()))
+            .collect();
+        self.journal = Some(MemoryJournal {
+            writes: Vec::new(),
+            devices,
+        });
+    }
+
+    /// Stops recording and returns what changed since `start_journal`.
+    pub(crate) fn take_journal(&mut self) -> MemoryJournal {
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// Puts back everything recorded in `journal`.
+    pub(crate) fn undo_journal(&mut self, journal: &MemoryJournal) -> Result<(), MemoryError> {
+        for (range, state) in &journal.devices {
+            self.load_device_state(range, state)?;
+        }
+        // Device registers were restored with their state; writing them
+        // again would repeat side effects such as printing
+        for write in journal.writes.iter().rev() {
+            if !self.is_mapped(write.address) {
+                if let Some(cell) = self.cells.get_mut(usize::from(write.address)) {
+                    *cell = write.old;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn journal_device(journal: &mut Option<MemoryJournal>, mapped: &MappedDevice) {
+        if let Some(journal) = journal {
+            if !journal
+                .devices
+                .iter()
+                .any(|(range, _)| *range == mapped.range)
+            {
+                journal
+                    .devices
+                    .push((mapped.range.clone(), mapped.device.save_state()));
+            }
+        }
+    }
+
+    /// Whether `address` is routed to a device rather than a memory cell.
+    pub fn is_mapped(&self, address: u16) -> bool {
+        self.device(address).is_some()
+    }
+
+    fn device(&self, address: u16) -> Option<&dyn Device> {
+        self.devices
+            .iter()
+            .find(|mapped| mapped.range.contains(&address))
+            .map(|mapped| mapped.device.as_ref())
     }
 
     pub fn write(&mut self, address: u16, value: u16) -> Result<(), MemoryError> {
+        if self.journal.is_some() {
+            let old = self.peek(address);
+            if let Some(journal) = &mut self.journal {
+                journal.writes.push(MemoryWrite {
+                    address,
+                    old,
+                    new: value,
+                });
+            }
+        }
+
+        if let Some(mapped) = self
+            .devices
+            .iter_mut()
+            .find(|mapped| mapped.range.contains(&address))
+        {
+            Self::journal_device(&mut self.journal, mapped);
+            return mapped.device.write(address, value, self.console.as_mut());
+        }
+
         if let Some(cell) = self.cells.get_mut::<usize>(address.into()) {
             *cell = value;
             Ok(())
@@ -38,13 +268,43 @@ impl Memory {
     }
 
     pub fn read(&mut self, address: usize) -> Option<u16> {
-        let keyboard_add: usize = MR_KBSR.into();
-        if address == keyboard_add {
-            self.handle_keyboard().ok()?;
+        if let Ok(address) = u16::try_from(address) {
+            if let Some(mapped) = self
+                .devices
+                .iter_mut()
+                .find(|mapped| mapped.range.contains(&address))
+            {
+                // Reads have side effects too, such as consuming a key
+                Self::journal_device(&mut self.journal, mapped);
+                return mapped.device.read(address, self.console.as_mut()).ok();
+            }
         }
         self.cells.get(address).copied()
     }
 
+    /// Takes the key latched in KBDR if there is one, otherwise blocks on the console.
+    pub fn read_key(&mut self) -> Result<u8, MemoryError> {
+        if self.peek(MR_KBSR) & KBSR_READY != 0 {
+            let key = self.read(MR_KBDR.into()).ok_or(MemoryError::Keyboard)?;
+            let [_, key] = key.to_be_bytes();
+            return Ok(key);
+        }
+
+        self.console
+            .read_byte()
+            .map_err(|err| MemoryError::KeyboardInput(err.to_string()))
+    }
+
+    pub fn peek(&self, address: u16) -> u16 {
+        if let Some(device) = self.device(address) {
+            return device.peek(address);
+        }
+        self.cells
+            .get::<usize>(address.into())
+            .copied()
+            .unwrap_or_default()
+    }
+
     pub fn load_program(&mut self, data: &[u16]) -> Result<(), MemoryError> {
         let origin: usize = match data.first() {
             Some(&value) => value.into(),
@@ -61,19 +321,214 @@ impl Memory {
         Ok(())
     }
 
-    fn handle_keyboard(&mut self) -> Result<(), MemoryError> {
-        let mut buffer = [0; 1];
-        std::io::stdin()
-            .read_exact(&mut buffer)
-            .map_err(|_| MemoryError::Keyboard)?;
+    /// False once a program clears MCR[15] to stop the clock. Without a
+    /// machine control device the clock never stops.
+    pub fn clock_enabled(&self) -> bool {
+        self.device(MR_MCR)
+            .is_none_or(|device| device.peek(MR_MCR) & MCR_CLOCK_ENABLE != 0)
+    }
 
-        if buffer[0] != 0 {
-            self.write(MR_KBSR, 1 << 15)?;
-            self.write(MR_KBDR, u16::from(*buffer.first().unwrap_or(&0)))?;
-        } else {
-            self.write(MR_KBSR, 0)?;
+    /// Clears MCR[15], as the HALT routine does.
+    pub fn stop_clock(&mut self) -> Result<(), MemoryError> {
+        if self.device(MR_MCR).is_none() {
+            return Ok(());
         }
+        self.write(MR_MCR, self.peek(MR_MCR) & !MCR_CLOCK_ENABLE)
+    }
 
-        Ok(())
+    /// Advances every device by one executed instruction.
+    pub fn tick(&mut self) {
+        for mapped in &mut self.devices {
+            mapped.device.tick();
+        }
+    }
+
+    /// The highest priority interrupt any device is requesting.
+    pub fn interrupt_request(&mut self) -> Option<InterruptRequest> {
+        let console = self.console.as_mut();
+        self.devices
+            .iter_mut()
+            .filter_map(|mapped| mapped.device.interrupt_request(console))
+            .max_by_key(|request| request.priority)
+    }
+}
+
+fn unmapped_range(range: &RangeInclusive<u16>) -> MemoryError {
+    MemoryError::DeviceState(format!(
+        "no device is mapped at x{:04X}-x{:04X}",
+        range.start(),
+        range.end()
+    ))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::console::BufferedConsole;
+    use crate::device::{DSR_READY, KBSR_INTERRUPT_ENABLE};
+    use crate::interrupt::{KEYBOARD_PRIORITY, KEYBOARD_VECTOR};
+
+    // Counts accesses and raises an interrupt once `pending` is set.
+    #[derive(Default)]
+    struct Counter {
+        reads: u16,
+        pending: bool,
+    }
+
+    impl Device for Counter {
+        fn read(&mut self, _address: u16, _console: &mut dyn Console) -> Result<u16, MemoryError> {
+            self.reads = self.reads.wrapping_add(1);
+            Ok(self.reads)
+        }
+
+        fn write(
+            &mut self,
+            _address: u16,
+            value: u16,
+            _console: &mut dyn Console,
+        ) -> Result<(), MemoryError> {
+            self.pending = value != 0;
+            Ok(())
+        }
+
+        fn peek(&self, _address: u16) -> u16 {
+            self.reads
+        }
+
+        fn interrupt_request(&mut self, _console: &mut dyn Console) -> Option<InterruptRequest> {
+            self.pending.then_some(InterruptRequest {
+                vector: 0x81,
+                priority: 6,
+            })
+        }
+    }
+
+    #[test]
+    fn test_keyboard_status_without_input() {
+        let mut memory = Memory::with_console(Box::new(BufferedConsole::new()));
+        assert_eq!(memory.read(MR_KBSR.into()), Some(0));
+    }
+
+    #[test]
+    fn test_keyboard_latches_key_until_read() {
+        let console = BufferedConsole::with_input("ab");
+        let mut memory = Memory::with_console(Box::new(console));
+
+        assert_eq!(memory.read(MR_KBSR.into()), Some(KBSR_READY));
+        assert_eq!(memory.read(MR_KBSR.into()), Some(KBSR_READY));
+        assert_eq!(memory.read(MR_KBDR.into()), Some(u16::from(b'a')));
+        assert_eq!(memory.peek(MR_KBSR), 0);
+
+        assert_eq!(memory.read(MR_KBSR.into()), Some(KBSR_READY));
+        assert_eq!(memory.read(MR_KBDR.into()), Some(u16::from(b'b')));
+        assert_eq!(memory.read(MR_KBSR.into()), Some(0));
+    }
+
+    #[test]
+    fn test_display_data_register_writes_to_console() {
+        let console = BufferedConsole::new();
+        let mut memory = Memory::with_console(Box::new(console.clone()));
+
+        assert_eq!(memory.read(MR_DSR.into()), Some(DSR_READY));
+        memory.write(MR_DDR, u16::from(b'H')).unwrap();
+        memory.write(MR_DDR, 0x0169).unwrap();
+        assert_eq!(console.output_string(), "Hi");
+
+        memory.write(MR_DSR, 0).unwrap();
+        assert_eq!(memory.read(MR_DSR.into()), Some(DSR_READY));
+    }
+
+    #[test]
+    fn test_machine_control_register() {
+        let mut memory = Memory::with_console(Box::new(BufferedConsole::new()));
+        assert_eq!(memory.peek(MR_MCR), MCR_CLOCK_ENABLE);
+        assert!(memory.clock_enabled());
+
+        memory.write(MR_MCR, 0x7FFF).unwrap();
+        assert!(!memory.clock_enabled());
+
+        memory.write(MR_MCR, 0xFFFF).unwrap();
+        memory.stop_clock().unwrap();
+        assert_eq!(memory.peek(MR_MCR), 0x7FFF);
+    }
+
+    #[test]
+    fn test_read_key_prefers_latched_key() {
+        let console = BufferedConsole::with_input("ab");
+        let mut memory = Memory::with_console(Box::new(console));
+
+        memory.read(MR_KBSR.into()).unwrap();
+        assert_eq!(memory.read_key().unwrap(), b'a');
+        assert_eq!(memory.read_key().unwrap(), b'b');
+        assert!(memory.read_key().is_err());
+    }
+
+    #[test]
+    fn test_keyboard_interrupt_request() {
+        let console = BufferedConsole::with_input("a");
+        let mut memory = Memory::with_console(Box::new(console));
+        assert_eq!(memory.interrupt_request(), None);
+
+        memory.write(MR_KBSR, KBSR_INTERRUPT_ENABLE).unwrap();
+        assert_eq!(
+            memory.interrupt_request(),
+            Some(InterruptRequest {
+                vector: KEYBOARD_VECTOR,
+                priority: KEYBOARD_PRIORITY
+            })
+        );
+
+        memory.read(MR_KBDR.into()).unwrap();
+        assert_eq!(memory.interrupt_request(), None);
+        assert_eq!(memory.peek(MR_KBSR), KBSR_INTERRUPT_ENABLE);
+    }
+
+    #[test]
+    fn test_custom_device() {
+        let mut memory = Memory::with_console(Box::new(BufferedConsole::with_input("a")));
+        memory
+            .map_device(0xFE10..=0xFE11, Box::new(Counter::default()))
+            .unwrap();
+
+        assert_eq!(memory.read(0xFE10), Some(1));
+        assert_eq!(memory.read(0xFE11), Some(2));
+        assert_eq!(memory.peek(0xFE10), 2);
+        assert_eq!(memory.cells.get(0xFE10).copied(), Some(0));
+
+        // The highest priority request wins
+        memory.write(MR_KBSR, KBSR_INTERRUPT_ENABLE).unwrap();
+        memory.write(0xFE10, 1).unwrap();
+        assert_eq!(
+            memory.interrupt_request(),
+            Some(InterruptRequest {
+                vector: 0x81,
+                priority: 6
+            })
+        );
+
+        assert!(memory.unmap_device(0xFE11).is_some());
+        assert_eq!(memory.read(0xFE10), Some(0));
+    }
+
+    #[test]
+    fn test_map_device_rejects_overlap() {
+        let mut memory = Memory::with_console(Box::new(BufferedConsole::new()));
+        assert!(matches!(
+            memory.map_device(0xFE02..=0xFE04, Box::new(Counter::default())),
+            Err(MemoryError::DeviceOverlap {
+                start: 0xFE02,
+                end: 0xFE04
+            })
+        ));
+    }
+
+    #[test]
+    fn test_without_devices() {
+        let mut memory = Memory::without_devices(Box::new(BufferedConsole::new()));
+        memory.write(MR_MCR, 0).unwrap();
+        assert!(memory.clock_enabled());
+        memory.write(MR_DDR, 0x41).unwrap();
+        assert_eq!(memory.read(MR_DDR.into()), Some(0x41));
     }
 }
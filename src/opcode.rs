@@ -1,5 +1,7 @@
+use thiserror::Error;
+
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
     OP_BR {
         n: bool,
@@ -83,7 +85,15 @@ pub enum OpcodeError {
     InvalidOpcode,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Error, Debug, PartialEq)]
+pub enum EncodeError {
+    #[error("Invalid register index: {0}")]
+    InvalidRegister(u16),
+    #[error("{value:#06x} does not fit in a {bits}-bit signed field")]
+    OutOfRange { value: u16, bits: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Trap {
     GetC,
     Out,
@@ -91,6 +101,8 @@ pub enum Trap {
     In,
     Putsp,
     Halt,
+    /// A vector with no built-in service routine.
+    Unknown(u16),
 }
 
 impl Opcode {
@@ -200,23 +212,8 @@ impl Opcode {
             }
             0b1111 => {
                 let value = instruction & 0b000_0000_1111_1111;
-                match value {
-                    0x20 => Ok(Opcode::OP_TRAP {
-                        trapvec: Trap::GetC,
-                    }),
-                    0x21 => Ok(Opcode::OP_TRAP { trapvec: Trap::Out }),
-                    0x22 => Ok(Opcode::OP_TRAP {
-                        trapvec: Trap::Puts,
-                    }),
-                    0x23 => Ok(Opcode::OP_TRAP { trapvec: Trap::In }),
-                    0x24 => Ok(Opcode::OP_TRAP {
-                        trapvec: Trap::Putsp,
-                    }),
-                    0x25 => Ok(Opcode::OP_TRAP {
-                        trapvec: Trap::Halt,
-                    }),
-                    _ => Err(OpcodeError::InvalidOpcode),
-                }
+                let trapvec = Trap::from_vector(value).unwrap_or(Trap::Unknown(value));
+                Ok(Opcode::OP_TRAP { trapvec })
             }
             0b1101 => Ok(Opcode::OP_RES),
             _ => Err(OpcodeError::InvalidOpcode),
@@ -224,6 +221,123 @@ impl Opcode {
     }
 }
 
+impl Opcode {
+    /// Encodes the instruction back into a machine word. Unused bits are zero,
+    /// except NOT which sets bits [5:0] as the ISA specifies.
+    pub fn encode(&self) -> Result<u16, EncodeError> {
+        let word = match *self {
+            Opcode::OP_BR { n, z, p, offset } => {
+                u16::from(n) << 11 | u16::from(z) << 10 | u16::from(p) << 9 | offset9(offset)?
+            }
+            Opcode::OP_ADD_REG { dr, sr1, sr2 } => {
+                0b0001 << 12 | register(dr)? << 9 | register(sr1)? << 6 | register(sr2)?
+            }
+            Opcode::OP_ADD_IMM { dr, sr1, imm5 } => {
+                0b0001 << 12 | register(dr)? << 9 | register(sr1)? << 6 | 1 << 5 | imm(imm5)?
+            }
+            Opcode::OP_LD { dr, offset } => 0b0010 << 12 | register(dr)? << 9 | offset9(offset)?,
+            Opcode::OP_ST { sr, offset } => 0b0011 << 12 | register(sr)? << 9 | offset9(offset)?,
+            Opcode::OP_JSR { offset } => 0b0100 << 12 | 1 << 11 | offset11(offset)?,
+            Opcode::OP_JSRR { base_r } => 0b0100 << 12 | register(base_r)? << 6,
+            Opcode::OP_AND_REG { dr, sr1, sr2 } => {
+                0b0101 << 12 | register(dr)? << 9 | register(sr1)? << 6 | register(sr2)?
+            }
+            Opcode::OP_AND_IMM { dr, sr1, imm5 } => {
+                0b0101 << 12 | register(dr)? << 9 | register(sr1)? << 6 | 1 << 5 | imm(imm5)?
+            }
+            Opcode::OP_LDR { dr, base_r, offset } => {
+                0b0110 << 12 | register(dr)? << 9 | register(base_r)? << 6 | offset6(offset)?
+            }
+            Opcode::OP_STR { sr, base_r, offset } => {
+                0b0111 << 12 | register(sr)? << 9 | register(base_r)? << 6 | offset6(offset)?
+            }
+            Opcode::OP_RTI => 0b1000 << 12,
+            Opcode::OP_NOT { dr, sr } => {
+                0b1001 << 12 | register(dr)? << 9 | register(sr)? << 6 | 0b11_1111
+            }
+            Opcode::OP_LDI { dr, offset } => 0b1010 << 12 | register(dr)? << 9 | offset9(offset)?,
+            Opcode::OP_STI { sr, offset } => 0b1011 << 12 | register(sr)? << 9 | offset9(offset)?,
+            Opcode::OP_JMP { base_r } => 0b1100 << 12 | register(base_r)? << 6,
+            Opcode::OP_RET => 0b1100 << 12 | 0b111 << 6,
+            Opcode::OP_RES => 0b1101 << 12,
+            Opcode::OP_LEA { dr, offset } => 0b1110 << 12 | register(dr)? << 9 | offset9(offset)?,
+            Opcode::OP_TRAP { trapvec } => 0b1111 << 12 | trap_vector(trapvec.vector())?,
+        };
+
+        Ok(word)
+    }
+}
+
+impl Trap {
+    pub fn from_vector(vector: u16) -> Option<Self> {
+        match vector {
+            0x20 => Some(Trap::GetC),
+            0x21 => Some(Trap::Out),
+            0x22 => Some(Trap::Puts),
+            0x23 => Some(Trap::In),
+            0x24 => Some(Trap::Putsp),
+            0x25 => Some(Trap::Halt),
+            _ => None,
+        }
+    }
+
+    pub fn vector(&self) -> u16 {
+        match self {
+            Trap::GetC => 0x20,
+            Trap::Out => 0x21,
+            Trap::Puts => 0x22,
+            Trap::In => 0x23,
+            Trap::Putsp => 0x24,
+            Trap::Halt => 0x25,
+            Trap::Unknown(vector) => *vector,
+        }
+    }
+}
+
+fn register(index: u16) -> Result<u16, EncodeError> {
+    if index <= 0b111 {
+        Ok(index)
+    } else {
+        Err(EncodeError::InvalidRegister(index))
+    }
+}
+
+// A sign-extended value fits a field when extending its low bits gives it back.
+fn signed_field(value: u16, bits: u32, sign_ext: fn(u16) -> u16) -> Result<u16, EncodeError> {
+    if sign_ext(value) != value {
+        return Err(EncodeError::OutOfRange { value, bits });
+    }
+    let mask = !(u16::MAX << bits);
+    Ok(value & mask)
+}
+
+fn trap_vector(vector: u16) -> Result<u16, EncodeError> {
+    if vector <= 0xFF {
+        Ok(vector)
+    } else {
+        Err(EncodeError::OutOfRange {
+            value: vector,
+            bits: 8,
+        })
+    }
+}
+
+fn imm(value: u16) -> Result<u16, EncodeError> {
+    signed_field(value, 5, sign_ext_imm5)
+}
+
+fn offset6(value: u16) -> Result<u16, EncodeError> {
+    signed_field(value, 6, sign_ext_imm6)
+}
+
+fn offset9(value: u16) -> Result<u16, EncodeError> {
+    signed_field(value, 9, sign_ext_imm9)
+}
+
+fn offset11(value: u16) -> Result<u16, EncodeError> {
+    signed_field(value, 11, sign_ext_imm11)
+}
+
 pub fn sign_ext_imm6(instruction: u16) -> u16 {
     let offset = instruction & 0b11_1111;
 
@@ -461,6 +575,14 @@ mod tests {
                 trapvec: Trap::GetC
             }
         );
+
+        let opcode = Opcode::from(0xF0FF)?;
+        assert_eq!(
+            opcode,
+            Opcode::OP_TRAP {
+                trapvec: Trap::Unknown(0xFF)
+            }
+        );
         Ok(())
     }
 
@@ -471,4 +593,122 @@ mod tests {
         assert_eq!(opcode, Opcode::OP_RES);
         Ok(())
     }
+
+    // Bits that `Opcode::from` actually reads for the given instruction.
+    fn significant_bits(instruction: u16) -> u16 {
+        match instruction >> 12 {
+            0b0001 | 0b0101 if instruction & 0b10_0000 == 0 => 0b1111_1111_1110_0111,
+            0b0100 if instruction & 0b1000_0000_0000 == 0 => 0b1111_1001_1100_0000,
+            0b1001 => 0b1111_1111_1100_0000,
+            0b1100 => 0b1111_0001_1100_0000,
+            0b1000 | 0b1101 => 0b1111_0000_0000_0000,
+            0b1111 => 0b1111_0000_1111_1111,
+            _ => 0xFFFF,
+        }
+    }
+
+    #[test]
+    fn test_encode_round_trip_all_words() -> Result<(), EncodeError> {
+        for instruction in 0..=u16::MAX {
+            let Ok(opcode) = Opcode::from(instruction) else {
+                continue;
+            };
+            let encoded = opcode.encode()?;
+            assert_eq!(Opcode::from(encoded), Ok(opcode), "{:#06x}", instruction);
+            assert_eq!(
+                (encoded ^ instruction) & significant_bits(instruction),
+                0,
+                "{:#06x} encoded as {:#06x}",
+                instruction,
+                encoded
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> Result<(), EncodeError> {
+        let opcode = Opcode::OP_ADD_IMM {
+            dr: 1,
+            sr1: 2,
+            imm5: 0xFFFD,
+        };
+        assert_eq!(opcode.encode()?, 0b0001_0010_1011_1101);
+
+        let opcode = Opcode::OP_BR {
+            n: true,
+            z: false,
+            p: true,
+            offset: 0xFF00,
+        };
+        assert_eq!(opcode.encode()?, 0b0000_1011_0000_0000);
+
+        assert_eq!(Opcode::OP_RET.encode()?, 0b1100_0001_1100_0000);
+        assert_eq!(
+            Opcode::OP_TRAP {
+                trapvec: Trap::Halt
+            }
+            .encode()?,
+            0xF025
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_rejects_invalid_fields() {
+        let opcode = Opcode::OP_NOT { dr: 8, sr: 1 };
+        assert_eq!(opcode.encode(), Err(EncodeError::InvalidRegister(8)));
+
+        let opcode = Opcode::OP_ADD_IMM {
+            dr: 0,
+            sr1: 0,
+            imm5: 16,
+        };
+        assert_eq!(
+            opcode.encode(),
+            Err(EncodeError::OutOfRange { value: 16, bits: 5 })
+        );
+
+        let opcode = Opcode::OP_LDR {
+            dr: 0,
+            base_r: 0,
+            offset: 0xFFDF,
+        };
+        assert_eq!(
+            opcode.encode(),
+            Err(EncodeError::OutOfRange {
+                value: 0xFFDF,
+                bits: 6
+            })
+        );
+
+        let opcode = Opcode::OP_TRAP {
+            trapvec: Trap::Unknown(0x100),
+        };
+        assert_eq!(
+            opcode.encode(),
+            Err(EncodeError::OutOfRange {
+                value: 0x100,
+                bits: 8
+            })
+        );
+
+        let opcode = Opcode::OP_LD { dr: 0, offset: 256 };
+        assert_eq!(
+            opcode.encode(),
+            Err(EncodeError::OutOfRange {
+                value: 256,
+                bits: 9
+            })
+        );
+
+        let opcode = Opcode::OP_JSR { offset: 0x0400 };
+        assert_eq!(
+            opcode.encode(),
+            Err(EncodeError::OutOfRange {
+                value: 0x0400,
+                bits: 11
+            })
+        );
+    }
 }
@@ -0,0 +1,32 @@
+use crate::assembler::{self, AssembleError, Program};
+
+/// Source of the operating system bundled with the VM: trap routines,
+/// exception handlers and the boot code that starts the user program.
+pub const SOURCE: &str = include_str!("../os/lc3os.asm");
+
+/// Where the image starts executing, in supervisor mode.
+pub const BOOT_ADDRESS: u16 = 0x0200;
+
+/// Assembles the bundled operating system.
+pub fn image() -> Result<Program, AssembleError> {
+    assembler::assemble(SOURCE)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_fills_system_space() {
+        let program = image().unwrap();
+        assert_eq!(program.origin, 0x0000);
+        assert_eq!(program.symbols.get("OS_START"), Some(&BOOT_ADDRESS));
+        assert!(program.words.len() < 0x3000);
+
+        let halt = program.symbols.get("TRAP_HALT").copied().unwrap();
+        assert_eq!(program.words.get(0x25).copied(), Some(halt));
+        let illegal = program.symbols.get("EXC_ILLEGAL").copied().unwrap();
+        assert_eq!(program.words.get(0x101).copied(), Some(illegal));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::console::Console;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// A byte of input and the instruction count at which the program read it
+/// from the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub instruction: u64,
+    pub byte: u8,
+}
+
+/// Parses an input log: one `<instruction count> <byte>` pair per line.
+pub fn parse_log(text: &str) -> io::Result<Vec<InputEvent>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let mut fields = line.split_whitespace();
+            let event = match (fields.next(), fields.next(), fields.next()) {
+                (Some(instruction), Some(byte), None) => instruction
+                    .parse()
+                    .ok()
+                    .zip(byte.parse().ok())
+                    .map(|(instruction, byte)| InputEvent { instruction, byte }),
+                _ => None,
+            };
+            event.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid input log line {}: {}",
+                        index.saturating_add(1),
+                        line
+                    ),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Passes everything through to another console and logs each input byte
+/// with the instruction count at which it was read.
+///
+/// Every entry is flushed as it is written, so the log survives the process
+/// being interrupted.
+pub struct RecordingConsole {
+    inner: Box<dyn Console>,
+    log: Box<dyn Write>,
+    instruction_count: u64,
+}
+
+impl RecordingConsole {
+    pub fn new(inner: impl Console + 'static, log: impl Write + 'static) -> Self {
+        Self {
+            inner: Box::new(inner),
+            log: Box::new(log),
+            instruction_count: 0,
+        }
+    }
+
+    fn record(&mut self, byte: u8) -> io::Result<u8> {
+        writeln!(self.log, "{} {}", self.instruction_count, byte)?;
+        self.log.flush()?;
+        Ok(byte)
+    }
+}
+
+impl Console for RecordingConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = self.inner.read_byte()?;
+        self.record(byte)
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        self.inner
+            .poll_byte()?
+            .map(|byte| self.record(byte))
+            .transpose()
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_bytes(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_instruction_count(&mut self, count: u64) {
+        self.instruction_count = count;
+        self.inner.set_instruction_count(count);
+    }
+}
+
+/// Feeds recorded input back at the instruction counts it was read at, so a
+/// run is repeated exactly. A poll before the next byte is due sees no input.
+/// Once the log runs out, input comes from the wrapped console again.
+pub struct ReplayConsole {
+    inner: Box<dyn Console>,
+    events: VecDeque<InputEvent>,
+    instruction_count: u64,
+}
+
+impl ReplayConsole {
+    pub fn new(inner: impl Console + 'static, events: Vec<InputEvent>) -> Self {
+        Self {
+            inner: Box::new(inner),
+            events: events.into(),
+            instruction_count: 0,
+        }
+    }
+
+    /// Recorded bytes not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+}
+
+impl Console for ReplayConsole {
+    // A blocking read happens at the recorded instruction in an identical run,
+    // so one anywhere else means the run has diverged from the recording
+    fn read_byte(&mut self) -> io::Result<u8> {
+        match self.events.front() {
+            Some(event) if event.instruction != self.instruction_count => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Replay diverged: key recorded at instruction {} was read at instruction {}",
+                    event.instruction, self.instruction_count
+                ),
+            )),
+            Some(_) => Ok(self.events.pop_front().map_or(0, |event| event.byte)),
+            None => self.inner.read_byte(),
+        }
+    }
+
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        match self.events.front() {
+            Some(event) if event.instruction <= self.instruction_count => {
+                Ok(self.events.pop_front().map(|event| event.byte))
+            }
+            Some(_) => Ok(None),
+            None => self.inner.poll_byte(),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_bytes(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_instruction_count(&mut self, count: u64) {
+        self.instruction_count = count;
+        self.inner.set_instruction_count(count);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferedConsole;
+    use crate::cpu::CPU;
+    use std::sync::{Arc, Mutex};
+
+    // Counts its polls of KBSR in R1 before echoing the key, so the result
+    // depends on when the key arrives
+    const POLL: &str = r#"
+            .ORIG x3000
+    LOOP    ADD R1, R1, #1
+            LDI R2, KBSR
+            BRzp LOOP
+            LDI R0, KBDR
+            OUT
+            GETC
+            OUT
+            HALT
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+            .END
+    "#;
+
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(console: impl Console + 'static) -> CPU {
+        let mut cpu = CPU::with_console(Box::new(console));
+        cpu.memory
+            .load_program(&assemble(POLL).unwrap().to_words())
+            .unwrap();
+        cpu.execute_program().unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_replay_delivers_input_at_recorded_instructions() {
+        let output = BufferedConsole::new();
+        let events = parse_log("10 97\n\n14 98\n").unwrap();
+        let cpu = run(ReplayConsole::new(output.clone(), events));
+
+        // LDI KBSR runs at instructions 1, 4, 7 and 10, GETC at 14
+        assert_eq!(cpu.r1, 4);
+        assert_eq!(output.output_string(), "ab");
+    }
+
+    #[test]
+    fn test_record_then_replay() {
+        let log = SharedLog::default();
+        let recorded = run(RecordingConsole::new(
+            BufferedConsole::with_input("xy"),
+            log.clone(),
+        ));
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, "1 120\n5 121\n");
+
+        let output = BufferedConsole::new();
+        let replayed = run(ReplayConsole::new(
+            output.clone(),
+            parse_log(&text).unwrap(),
+        ));
+        assert_eq!(replayed.r1, recorded.r1);
+        assert_eq!(replayed.instruction_count, recorded.instruction_count);
+        assert_eq!(output.output_string(), "xy");
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let mut console = ReplayConsole::new(BufferedConsole::new(), parse_log("5 121\n").unwrap());
+        console.set_instruction_count(4);
+        let err = console.read_byte().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Replay diverged: key recorded at instruction 5 was read at instruction 4"
+        );
+        assert_eq!(console.remaining(), 1);
+
+        console.set_instruction_count(5);
+        assert_eq!(console.read_byte().unwrap(), b'y');
+    }
+
+    #[test]
+    fn test_replay_falls_back_to_inner_console() {
+        let mut console = ReplayConsole::new(BufferedConsole::with_input("z"), Vec::new());
+        assert_eq!(console.remaining(), 0);
+        assert_eq!(console.poll_byte().unwrap(), Some(b'z'));
+    }
+
+    #[test]
+    fn test_parse_log_errors() {
+        let err = parse_log("1 97\n2 300\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Invalid input log line 2: 2 300");
+        assert!(parse_log("1 2 3").is_err());
+    }
+}
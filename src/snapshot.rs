@@ -0,0 +1,376 @@
+use crate::cpu::{ExceptionMode, TrapMode, CPU};
+use crate::memory::MemoryError;
+use std::ops::RangeInclusive;
+use thiserror::Error;
+
+pub const MAGIC: [u8; 4] = *b"LC3S";
+pub const VERSION: u16 = 1;
+
+const FLAG_RUNNING: u8 = 1;
+const FLAG_STRICT: u8 = 1 << 1;
+const FLAG_TRAP_TABLE: u8 = 1 << 2;
+const FLAG_TRAP_EXCEPTION: u8 = 1 << 3;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Not an LC-3 snapshot")]
+    Magic,
+    #[error("Unsupported snapshot version {0}")]
+    Version(u16),
+    #[error("The snapshot is truncated")]
+    Truncated,
+    #[error("Corrupt snapshot: {0}")]
+    Corrupt(String),
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+}
+
+/// The complete state of a machine: registers, every memory cell and the
+/// state of each mapped device.
+///
+/// In bytes, everything big-endian like `.obj` files:
+///
+/// ```text
+/// "LC3S" version:u16
+/// R0..R7 PC IR PSR SSP USP :u16  flags:u8  instruction_count:u64
+/// runs:u32 { start:u16 length:u32 words:u16[length] }   non-zero memory
+/// devices:u16 { start:u16 end:u16 length:u16 words:u16[length] }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub registers: [u16; 8],
+    pub pc: u16,
+    pub ir: u16,
+    pub psr: u16,
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    pub running: bool,
+    pub exception_mode: ExceptionMode,
+    pub trap_mode: TrapMode,
+    pub instruction_count: u64,
+    pub cells: Vec<u16>,
+    pub devices: Vec<(RangeInclusive<u16>, Vec<u16>)>,
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &CPU) -> Self {
+        Self {
+            registers: [
+                cpu.r0, cpu.r1, cpu.r2, cpu.r3, cpu.r4, cpu.r5, cpu.r6, cpu.r7,
+            ],
+            pc: cpu.pc,
+            ir: cpu.ir,
+            psr: cpu.psr,
+            saved_ssp: cpu.saved_ssp,
+            saved_usp: cpu.saved_usp,
+            running: cpu.running,
+            exception_mode: cpu.exception_mode,
+            trap_mode: cpu.trap_mode,
+            instruction_count: cpu.instruction_count,
+            cells: cpu.memory.cells.to_vec(),
+            devices: cpu.memory.device_states(),
+        }
+    }
+
+    /// Puts `cpu` back in the captured state. The devices must be mapped at
+    /// the same ranges as when the snapshot was taken; if they are not, `cpu`
+    /// is left untouched.
+    pub fn restore(&self, cpu: &mut CPU) -> Result<(), SnapshotError> {
+        if self.cells.len() != cpu.memory.cells.len() {
+            return Err(SnapshotError::Corrupt(format!(
+                "memory has {} cells",
+                self.cells.len()
+            )));
+        }
+        cpu.memory.load_device_states(&self.devices)?;
+
+        [
+            cpu.r0, cpu.r1, cpu.r2, cpu.r3, cpu.r4, cpu.r5, cpu.r6, cpu.r7,
+        ] = self.registers;
+        cpu.pc = self.pc;
+        cpu.ir = self.ir;
+        cpu.psr = self.psr;
+        cpu.saved_ssp = self.saved_ssp;
+        cpu.saved_usp = self.saved_usp;
+        cpu.running = self.running;
+        cpu.exception_mode = self.exception_mode;
+        cpu.trap_mode = self.trap_mode;
+        cpu.instruction_count = self.instruction_count;
+        for (cell, value) in cpu.memory.cells.iter_mut().zip(&self.cells) {
+            *cell = *value;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_be_bytes());
+        for word in self.registers.iter().chain(&[
+            self.pc,
+            self.ir,
+            self.psr,
+            self.saved_ssp,
+            self.saved_usp,
+        ]) {
+            bytes.extend(word.to_be_bytes());
+        }
+        let mut flags = 0;
+        if self.running {
+            flags |= FLAG_RUNNING;
+        }
+        if self.exception_mode == ExceptionMode::Strict {
+            flags |= FLAG_STRICT;
+        }
+        match self.trap_mode {
+            TrapMode::Native => {}
+            TrapMode::VectorTable => flags |= FLAG_TRAP_TABLE,
+            TrapMode::Exception => flags |= FLAG_TRAP_EXCEPTION,
+        }
+        bytes.push(flags);
+        bytes.extend(self.instruction_count.to_be_bytes());
+
+        // Only runs of non-zero cells are stored, most of memory is empty
+        let runs = nonzero_runs(&self.cells);
+        bytes.extend(u32::try_from(runs.len()).unwrap_or(u32::MAX).to_be_bytes());
+        for (start, run) in runs {
+            bytes.extend(start.to_be_bytes());
+            bytes.extend(u32::try_from(run.len()).unwrap_or(u32::MAX).to_be_bytes());
+            bytes.extend(run.iter().flat_map(|word| word.to_be_bytes()));
+        }
+
+        bytes.extend(
+            u16::try_from(self.devices.len())
+                .unwrap_or(u16::MAX)
+                .to_be_bytes(),
+        );
+        for (range, state) in &self.devices {
+            bytes.extend(range.start().to_be_bytes());
+            bytes.extend(range.end().to_be_bytes());
+            bytes.extend(u16::try_from(state.len()).unwrap_or(u16::MAX).to_be_bytes());
+            bytes.extend(state.iter().flat_map(|word| word.to_be_bytes()));
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::Magic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+
+        let mut registers = [0; 8];
+        for register in &mut registers {
+            *register = reader.u16()?;
+        }
+        let [pc, ir, psr, saved_ssp, saved_usp] = [
+            reader.u16()?,
+            reader.u16()?,
+            reader.u16()?,
+            reader.u16()?,
+            reader.u16()?,
+        ];
+        let flags = reader.u8()?;
+        let instruction_count = reader.u64()?;
+
+        let mut cells = vec![0; 1 << 16];
+        for _ in 0..reader.u32()? {
+            let start = reader.u16()?;
+            let length = usize::try_from(reader.u32()?)
+                .map_err(|_| SnapshotError::Corrupt("memory run too long".to_string()))?;
+            let run = usize::from(start)
+                .checked_add(length)
+                .and_then(|end| cells.get_mut(usize::from(start)..end))
+                .ok_or_else(|| {
+                    SnapshotError::Corrupt(format!("memory run at x{:04X} overflows", start))
+                })?;
+            for cell in run {
+                *cell = reader.u16()?;
+            }
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..reader.u16()? {
+            let range = reader.u16()?..=reader.u16()?;
+            let state = (0..reader.u16()?)
+                .map(|_| reader.u16())
+                .collect::<Result<_, _>>()?;
+            devices.push((range, state));
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(SnapshotError::Corrupt(format!(
+                "{} trailing bytes",
+                reader.bytes.len()
+            )));
+        }
+
+        Ok(Self {
+            registers,
+            pc,
+            ir,
+            psr,
+            saved_ssp,
+            saved_usp,
+            running: flags & FLAG_RUNNING != 0,
+            exception_mode: if flags & FLAG_STRICT != 0 {
+                ExceptionMode::Strict
+            } else {
+                ExceptionMode::Lenient
+            },
+            trap_mode: if flags & FLAG_TRAP_EXCEPTION != 0 {
+                TrapMode::Exception
+            } else if flags & FLAG_TRAP_TABLE != 0 {
+                TrapMode::VectorTable
+            } else {
+                TrapMode::Native
+            },
+            instruction_count,
+            cells,
+            devices,
+        })
+    }
+}
+
+fn nonzero_runs(cells: &[u16]) -> Vec<(u16, &[u16])> {
+    let mut runs = Vec::new();
+    let mut rest = cells;
+    let mut address: usize = 0;
+    while let Some(start) = rest.iter().position(|cell| *cell != 0) {
+        let tail = rest.get(start..).unwrap_or_default();
+        let length = tail
+            .iter()
+            .position(|cell| *cell == 0)
+            .unwrap_or(tail.len());
+        let (run, remaining) = tail.split_at(length);
+        address = address.saturating_add(start);
+        runs.push((u16::try_from(address).unwrap_or(u16::MAX), run));
+        address = address.saturating_add(length);
+        rest = remaining;
+    }
+    runs
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SnapshotError> {
+        let (taken, rest) = self
+            .bytes
+            .split_at_checked(count)
+            .ok_or(SnapshotError::Truncated)?;
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        self.take(N)?
+            .try_into()
+            .map_err(|_| SnapshotError::Truncated)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(u8::from_be_bytes(self.array()?))
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::console::BufferedConsole;
+    use crate::device::{MR_KBDR, MR_KBSR, MR_MCR, MR_TMI, MR_TMR};
+
+    #[test]
+    fn test_round_trip() {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::with_input("k")));
+        cpu.r3 = 0x1234;
+        cpu.pc = 0x3005;
+        cpu.psr = 0x8004;
+        cpu.saved_ssp = 0x2FFE;
+        cpu.instruction_count = 42;
+        cpu.trap_mode = TrapMode::Exception;
+        cpu.memory.write(0x3000, 0xF025).unwrap();
+        cpu.memory.write(0xFFFD, 7).unwrap();
+        cpu.memory.write(MR_TMI, 9).unwrap();
+        cpu.memory.read(usize::from(MR_KBSR)).unwrap();
+
+        let bytes = Snapshot::capture(&cpu).to_bytes();
+        assert_eq!(bytes.get(..4).unwrap(), b"LC3S");
+        assert!(bytes.len() < 200);
+
+        let mut restored = CPU::with_console(Box::new(BufferedConsole::new()));
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        snapshot.restore(&mut restored).unwrap();
+        assert_eq!(snapshot, Snapshot::capture(&cpu));
+        assert_eq!(restored.r3, 0x1234);
+        assert_eq!(restored.pc, 0x3005);
+        assert_eq!(restored.psr, 0x8004);
+        assert_eq!(restored.saved_ssp, 0x2FFE);
+        assert_eq!(restored.instruction_count, 42);
+        assert_eq!(restored.trap_mode, TrapMode::Exception);
+        assert_eq!(restored.exception_mode, ExceptionMode::Lenient);
+        assert_eq!(restored.memory.peek(0x3000), 0xF025);
+        assert_eq!(restored.memory.peek(0xFFFD), 7);
+        assert_eq!(restored.memory.peek(MR_TMI), 9);
+        assert_eq!(restored.memory.peek(MR_TMR), 0);
+        // The latched key survives the snapshot
+        assert_eq!(restored.memory.peek(MR_KBDR), u16::from(b'k'));
+        assert_eq!(restored.memory.peek(MR_MCR), 0x8000);
+    }
+
+    #[test]
+    fn test_rejects_bad_snapshots() {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::with_input("k")));
+        cpu.memory.read(usize::from(MR_KBSR)).unwrap();
+        let bytes = Snapshot::capture(&cpu).to_bytes();
+
+        assert!(matches!(
+            Snapshot::from_bytes(b"ELF!"),
+            Err(SnapshotError::Magic)
+        ));
+        let mut future = bytes.clone();
+        *future.get_mut(5).unwrap() = 9;
+        assert!(matches!(
+            Snapshot::from_bytes(&future),
+            Err(SnapshotError::Version(9))
+        ));
+        assert!(matches!(
+            Snapshot::from_bytes(bytes.get(..bytes.len() - 1).unwrap()),
+            Err(SnapshotError::Truncated)
+        ));
+
+        // Saved with a device the restoring machine does not have
+        let mut bare = CPU::with_console(Box::new(BufferedConsole::new()));
+        bare.memory.unmap_device(MR_TMR);
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes).unwrap().restore(&mut bare),
+            Err(SnapshotError::Memory(MemoryError::DeviceState(_)))
+        ));
+        // Not even the devices it does have are restored
+        assert_eq!(bare.memory.peek(MR_KBDR), 0);
+    }
+
+    #[test]
+    fn test_nonzero_runs() {
+        let cells = [0, 1, 2, 0, 0, 3];
+        assert_eq!(nonzero_runs(&cells), vec![(1, &[1, 2][..]), (5, &[3][..])]);
+    }
+}
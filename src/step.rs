@@ -0,0 +1,50 @@
+use crate::interrupt::InterruptRequest;
+use crate::opcode::{Opcode, Trap};
+
+/// Everything a single instruction did to the machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepOutcome {
+    pub pc_before: u16,
+    /// Interrupt taken before the fetch; `pc_before` is the interrupted PC.
+    pub interrupt: Option<InterruptRequest>,
+    pub pc_after: u16,
+    /// Where `instruction` was fetched from. Differs from `pc_before` when an
+    /// interrupt or exception was taken first.
+    pub address: u16,
+    pub instruction: u16,
+    pub opcode: Opcode,
+    pub register_writes: Vec<RegisterWrite>,
+    pub memory_reads: Vec<MemoryRead>,
+    pub memory_writes: Vec<MemoryWrite>,
+    pub trap: Option<Trap>,
+    pub halted: bool,
+}
+
+/// A write to R0..R7 (index 0..7) or the PC (index 8).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterWrite {
+    pub index: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryRead {
+    pub address: u16,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Accesses collected by the CPU while an instruction executes.
+#[derive(Debug, Default)]
+pub(crate) struct AccessLog {
+    pub register_writes: Vec<RegisterWrite>,
+    pub memory_reads: Vec<MemoryRead>,
+    pub memory_writes: Vec<MemoryWrite>,
+}
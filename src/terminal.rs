@@ -0,0 +1,95 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io::{self, IsTerminal};
+use std::os::fd::RawFd;
+use std::{panic, process, thread};
+use termios::*;
+
+const STDIN: RawFd = 0;
+
+/// Puts the terminal in non-canonical, no-echo mode and restores the original
+/// settings when dropped, on panic and on SIGINT/SIGTERM.
+pub struct TerminalGuard {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl TerminalGuard {
+    /// Returns `None` when stdin is not a terminal, so the VM runs headless.
+    pub fn raw_mode() -> io::Result<Option<Self>> {
+        if !io::stdin().is_terminal() {
+            return Ok(None);
+        }
+
+        let guard = Self::new(STDIN)?;
+        let original = guard.original;
+
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore(STDIN, &original);
+            previous_hook(info);
+        }));
+
+        // Exits with the shell's 128 + signal status: 130 for SIGINT, 143 for
+        // SIGTERM
+        match Signals::new([SIGINT, SIGTERM]) {
+            Ok(mut signals) => {
+                thread::spawn(move || {
+                    if let Some(signal) = signals.forever().next() {
+                        restore(STDIN, &original);
+                        process::exit(signal.saturating_add(128));
+                    }
+                });
+            }
+            Err(err) => eprintln!("Failed to install signal handler: {}", err),
+        }
+
+        Ok(Some(guard))
+    }
+
+    fn new(fd: RawFd) -> io::Result<Self> {
+        let original = Termios::from_fd(fd)?;
+        let mut raw = original;
+        raw.c_lflag &= !(ICANON | ECHO);
+        tcsetattr(fd, TCSANOW, &raw)?;
+        Ok(Self { fd, original })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore(self.fd, &self.original);
+    }
+}
+
+fn restore(fd: RawFd, original: &Termios) {
+    if let Err(err) = tcsetattr(fd, TCSANOW, original) {
+        eprintln!("Failed to restore terminal: {}", err);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_guard_restores_on_drop() {
+        // A fresh pseudo-terminal, so the test does not depend on how it is run
+        let pty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/ptmx")
+            .unwrap();
+        let fd = pty.as_raw_fd();
+        let before = Termios::from_fd(fd).unwrap();
+        assert_ne!(before.c_lflag & (ICANON | ECHO), 0);
+
+        let guard = TerminalGuard::new(fd).unwrap();
+        assert_eq!(Termios::from_fd(fd).unwrap().c_lflag & (ICANON | ECHO), 0);
+        drop(guard);
+        assert_eq!(Termios::from_fd(fd).unwrap(), before);
+    }
+}
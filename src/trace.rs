@@ -0,0 +1,303 @@
+use crate::cpu::CPUError;
+use crate::flags::ConditionFlags;
+use crate::opcode::Opcode;
+use crate::step::StepOutcome;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// One aligned line per instruction, for reading and diffing.
+    #[default]
+    Human,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// Writes a line for every executed instruction.
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+}
+
+impl Tracer {
+    pub fn new(writer: impl Write + 'static, format: TraceFormat) -> Self {
+        Self {
+            writer: Box::new(writer),
+            format,
+        }
+    }
+
+    /// Records the instruction numbered `cycle`, with the condition codes it left.
+    pub fn record(&mut self, cycle: u64, outcome: &StepOutcome, cond: u16) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Human => human_line(cycle, outcome, cond),
+            TraceFormat::JsonLines => json_line(cycle, outcome, cond),
+        };
+        writeln!(self.writer, "{}", line)
+    }
+
+    /// Records the instruction numbered `cycle`, at `address`, that failed
+    /// with `error` instead of completing.
+    pub fn record_error(
+        &mut self,
+        cycle: u64,
+        address: u16,
+        instruction: u16,
+        error: &CPUError,
+    ) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Human => human_error_line(cycle, address, instruction, error),
+            TraceFormat::JsonLines => json_error_line(cycle, address, instruction, error),
+        };
+        writeln!(self.writer, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn register_name(index: u16) -> String {
+    match index {
+        8 => "PC".to_string(),
+        index => format!("R{}", index),
+    }
+}
+
+fn cond_name(cond: u16) -> &'static str {
+    match cond {
+        c if c == u16::from(ConditionFlags::NEG) => "N",
+        c if c == u16::from(ConditionFlags::ZRO) => "Z",
+        c if c == u16::from(ConditionFlags::POS) => "P",
+        _ => "-",
+    }
+}
+
+// Writing to a String cannot fail, so the results of `write!` are ignored.
+pub fn human_line(cycle: u64, outcome: &StepOutcome, cond: u16) -> String {
+    let mut line = format!(
+        "{:>8}  x{:04X}  x{:04X}  {:<24}  CC={}",
+        cycle,
+        outcome.address,
+        outcome.instruction,
+        outcome.opcode.at(outcome.address).to_string(),
+        cond_name(cond)
+    );
+    if let Some(interrupt) = outcome.interrupt {
+        let _ = write!(line, "  INT x{:02X}", interrupt.vector);
+    }
+    for write in &outcome.register_writes {
+        let _ = write!(
+            line,
+            "  {}:x{:04X}->x{:04X}",
+            register_name(write.index),
+            write.old,
+            write.new
+        );
+    }
+    for read in &outcome.memory_reads {
+        let _ = write!(line, "  rd[x{:04X}]=x{:04X}", read.address, read.value);
+    }
+    for write in &outcome.memory_writes {
+        let _ = write!(
+            line,
+            "  wr[x{:04X}]:x{:04X}->x{:04X}",
+            write.address, write.old, write.new
+        );
+    }
+    if outcome.halted {
+        line.push_str("  HALTED");
+    }
+    line
+}
+
+pub fn json_line(cycle: u64, outcome: &StepOutcome, cond: u16) -> String {
+    let registers: Vec<String> = outcome
+        .register_writes
+        .iter()
+        .map(|write| {
+            format!(
+                r#"{{"register":"{}","old":{},"new":{}}}"#,
+                register_name(write.index),
+                write.old,
+                write.new
+            )
+        })
+        .collect();
+    let reads: Vec<String> = outcome
+        .memory_reads
+        .iter()
+        .map(|read| format!(r#"{{"address":{},"value":{}}}"#, read.address, read.value))
+        .collect();
+    let writes: Vec<String> = outcome
+        .memory_writes
+        .iter()
+        .map(|write| {
+            format!(
+                r#"{{"address":{},"old":{},"new":{}}}"#,
+                write.address, write.old, write.new
+            )
+        })
+        .collect();
+    let interrupt = match outcome.interrupt {
+        Some(interrupt) => interrupt.vector.to_string(),
+        None => "null".to_string(),
+    };
+
+    format!(
+        r#"{{"cycle":{},"pc":{},"instruction":{},"disasm":"{}","cc":"{}","interrupt":{},"registers":[{}],"reads":[{}],"writes":[{}],"halted":{}}}"#,
+        cycle,
+        outcome.address,
+        outcome.instruction,
+        json_escape(&outcome.opcode.at(outcome.address).to_string()),
+        cond_name(cond),
+        interrupt,
+        registers.join(","),
+        reads.join(","),
+        writes.join(","),
+        outcome.halted
+    )
+}
+
+fn disassemble(address: u16, instruction: u16) -> String {
+    match Opcode::from(instruction) {
+        Ok(opcode) => opcode.at(address).to_string(),
+        Err(_) => format!(".FILL x{:04X}", instruction),
+    }
+}
+
+pub fn human_error_line(cycle: u64, address: u16, instruction: u16, error: &CPUError) -> String {
+    format!(
+        "{:>8}  x{:04X}  x{:04X}  {:<24}  ERROR {}",
+        cycle,
+        address,
+        instruction,
+        disassemble(address, instruction),
+        error
+    )
+}
+
+pub fn json_error_line(cycle: u64, address: u16, instruction: u16, error: &CPUError) -> String {
+    format!(
+        r#"{{"cycle":{},"pc":{},"instruction":{},"disasm":"{}","error":"{}"}}"#,
+        cycle,
+        address,
+        instruction,
+        json_escape(&disassemble(address, instruction)),
+        json_escape(&error.to_string())
+    )
+}
+
+pub(crate) fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::console::BufferedConsole;
+    use crate::cpu::CPU;
+    use std::cell::RefCell;
+    use std::io::BufWriter;
+    use std::rc::Rc;
+
+    fn outcome() -> (StepOutcome, u16) {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        // LDR R1, R2, #0 ; with R2 pointing at x4000
+        cpu.memory.write(0x3000, 0x6280).unwrap();
+        cpu.memory.write(0x4000, 0x8001).unwrap();
+        cpu.r2 = 0x4000;
+        let outcome = cpu.step().unwrap();
+        (outcome, cpu.cond())
+    }
+
+    #[test]
+    fn test_human_line() {
+        let (outcome, cond) = outcome();
+        assert_eq!(
+            human_line(1, &outcome, cond),
+            "       1  x3000  x6280  LDR R1, R2, #0            CC=N  R1:x0000->x8001  rd[x4000]=x8001"
+        );
+    }
+
+    #[test]
+    fn test_json_line() {
+        let (outcome, cond) = outcome();
+        assert_eq!(
+            json_line(1, &outcome, cond),
+            r#"{"cycle":1,"pc":12288,"instruction":25216,"disasm":"LDR R1, R2, #0","cc":"N","interrupt":null,"registers":[{"register":"R1","old":0,"new":32769}],"reads":[{"address":16384,"value":32769}],"writes":[],"halted":false}"#
+        );
+        assert_eq!(json_escape("a\"b\n"), "a\\\"b\\u000a");
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedTrace(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedTrace {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedTrace {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn traced_cpu(words: &[u16]) -> (CPU, SharedTrace) {
+        let trace = SharedTrace::default();
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.tracer = Some(Tracer::new(
+            BufWriter::new(trace.clone()),
+            TraceFormat::Human,
+        ));
+        cpu.memory.load_program(words).unwrap();
+        (cpu, trace)
+    }
+
+    #[test]
+    fn test_trace_records_failing_instruction() {
+        // ADD R1, R1, #1 then RES
+        let (mut cpu, trace) = traced_cpu(&[0x3000, 0x1261, 0xD000]);
+        assert!(cpu.execute_program().is_err());
+        let text = trace.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines.get(1).unwrap(),
+            &"       2  x3001  xD000  RES                       ERROR Illegal opcode at x3001 (instruction xD000)"
+        );
+
+        let error = CPUError::Execute("GetC".to_string());
+        assert_eq!(
+            json_error_line(7, 0x3000, 0xF020, &error),
+            r#"{"cycle":7,"pc":12288,"instruction":61472,"disasm":"GETC","error":"Fail executing instruction: GetC"}"#
+        );
+    }
+
+    #[test]
+    fn test_trace_is_flushed_when_the_budget_runs_out() {
+        // BR #-1, looping forever
+        let (mut cpu, trace) = traced_cpu(&[0x3000, 0x0FFF]);
+        cpu.run_for(3).unwrap();
+        assert_eq!(trace.text().lines().count(), 3);
+    }
+}
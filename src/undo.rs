@@ -0,0 +1,255 @@
+use crate::cpu::CPU;
+use crate::memory::MemoryError;
+use crate::step::{MemoryWrite, RegisterWrite};
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+/// How many instructions the debugger can step back by default.
+pub const DEFAULT_UNDO_CAPACITY: usize = 100_000;
+
+/// Old values of the memory cells and device registers an instruction wrote,
+/// and the state of the devices it touched.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryJournal {
+    /// Every write, device registers included, in the order it happened.
+    pub writes: Vec<MemoryWrite>,
+    /// Device states from before the instruction first accessed each device.
+    pub devices: Vec<(RangeInclusive<u16>, Vec<u16>)>,
+}
+
+/// What one instruction changed, recorded as it ran: the old values of the
+/// registers and memory it wrote, plus the PC, PSR, saved stack pointers and
+/// counters from before it.
+///
+/// Console input is not given back. Undoing an instruction that took a key
+/// from the console, through GETC, IN or a keyboard poll, loses the key, and
+/// running forward again reads the next one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndoRecord {
+    pub pc: u16,
+    pub ir: u16,
+    pub psr: u16,
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    pub running: bool,
+    pub instruction_count: u64,
+    /// Writes through `CPU::update_register`, in the order they happened.
+    pub register_writes: Vec<RegisterWrite>,
+    pub memory: MemoryJournal,
+}
+
+impl UndoRecord {
+    /// A record for the instruction `cpu` is about to run. The writes are
+    /// filled in once it has run.
+    pub(crate) fn new(cpu: &CPU) -> Self {
+        Self {
+            pc: cpu.pc,
+            ir: cpu.ir,
+            psr: cpu.psr,
+            saved_ssp: cpu.saved_ssp,
+            saved_usp: cpu.saved_usp,
+            running: cpu.running,
+            instruction_count: cpu.instruction_count,
+            register_writes: Vec::new(),
+            memory: MemoryJournal::default(),
+        }
+    }
+
+    /// Puts `cpu` back in the state from before the instruction.
+    pub(crate) fn restore(&self, cpu: &mut CPU) -> Result<(), MemoryError> {
+        cpu.memory.undo_journal(&self.memory)?;
+        for write in self.register_writes.iter().rev() {
+            if let Ok(register) = cpu.get_register(write.index) {
+                *register = write.old;
+            }
+        }
+
+        cpu.pc = self.pc;
+        cpu.ir = self.ir;
+        cpu.psr = self.psr;
+        cpu.saved_ssp = self.saved_ssp;
+        cpu.saved_usp = self.saved_usp;
+        cpu.running = self.running;
+        cpu.instruction_count = self.instruction_count;
+        Ok(())
+    }
+
+    /// Whether the instruction wrote R0..R7 (index 0..7), even with an
+    /// unchanged value.
+    pub fn wrote_register(&self, index: u16) -> bool {
+        self.register_writes
+            .iter()
+            .any(|write| write.index == index)
+    }
+
+    pub fn wrote_memory(&self, address: u16) -> bool {
+        self.memory
+            .writes
+            .iter()
+            .any(|write| write.address == address)
+    }
+}
+
+/// The most recent `capacity` undo records; older ones are dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndoLog {
+    records: VecDeque<UndoRecord>,
+    capacity: usize,
+}
+
+impl UndoLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::console::BufferedConsole;
+
+    fn record(instruction_count: u64) -> UndoRecord {
+        let mut cpu = CPU::with_console(Box::new(BufferedConsole::new()));
+        cpu.instruction_count = instruction_count;
+        UndoRecord::new(&cpu)
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let mut log = UndoLog::new(2);
+        for count in 0..3 {
+            log.push(record(count));
+        }
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.pop().unwrap().instruction_count, 2);
+        assert_eq!(log.pop().unwrap().instruction_count, 1);
+        assert!(log.pop().is_none());
+
+        let mut disabled = UndoLog::new(0);
+        disabled.push(record(0));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_step_back_restores_state() {
+        let console = BufferedConsole::new();
+        let mut cpu = CPU::with_console(Box::new(console.clone()));
+        cpu.undo_log = Some(UndoLog::new(16));
+        let source = "
+            .ORIG x3000
+            ADD R1, R1, #5
+            ST R1, SLOT
+            STI R1, DDR
+            HALT
+    SLOT    .FILL x1234
+    DDR     .FILL xFE06
+            .END
+        ";
+        cpu.memory
+            .load_program(&assemble(source).unwrap().to_words())
+            .unwrap();
+        cpu.execute_program().unwrap();
+        assert!(!cpu.running);
+        assert_eq!(cpu.memory.peek(0x3004), 5);
+
+        // Undoing HALT restarts the clock
+        cpu.step_back().unwrap().unwrap();
+        assert!(cpu.running);
+        assert!(cpu.memory.clock_enabled());
+        let sti = cpu.step_back().unwrap().unwrap();
+        assert!(sti.wrote_memory(0xFE06));
+        assert_eq!(cpu.memory.peek(0xFE06), 0);
+        let st = cpu.step_back().unwrap().unwrap();
+        assert!(st.wrote_memory(0x3004));
+        assert_eq!(cpu.memory.peek(0x3004), 0x1234);
+        let add = cpu.step_back().unwrap().unwrap();
+        assert!(add.wrote_register(1));
+        assert!(!add.wrote_register(2));
+        assert_eq!(cpu.r1, 0);
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.instruction_count, 0);
+        assert!(cpu.step_back().unwrap().is_none());
+
+        // Running forward again repeats the program, output included
+        cpu.execute_program().unwrap();
+        assert_eq!(cpu.memory.peek(0x3004), 5);
+        assert_eq!(console.output(), vec![5, 5]);
+    }
+
+    #[test]
+    fn test_records_only_what_changed() {
+        let console = BufferedConsole::with_input("k");
+        let mut cpu = CPU::with_console(Box::new(console));
+        cpu.undo_log = Some(UndoLog::new(16));
+        let source = "
+            .ORIG x3000
+            ADD R1, R1, #1
+            LDI R2, KBSR
+            LDI R0, KBDR
+            HALT
+    KBSR    .FILL xFE00
+    KBDR    .FILL xFE02
+            .END
+        ";
+        cpu.memory
+            .load_program(&assemble(source).unwrap().to_words())
+            .unwrap();
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.r0, u16::from(b'k'));
+
+        // Undoing the KBDR read latches the key again
+        let read = cpu.step_back().unwrap().unwrap();
+        assert_eq!(read.memory.devices.len(), 1);
+        assert_eq!(cpu.memory.peek(0xFE00) & 0x8000, 0x8000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.r0, u16::from(b'k'));
+        cpu.step_back().unwrap().unwrap();
+
+        cpu.step_back().unwrap().unwrap();
+        let add = cpu.step_back().unwrap().unwrap();
+        assert_eq!(
+            add.register_writes,
+            vec![RegisterWrite {
+                index: 1,
+                old: 0,
+                new: 1
+            }]
+        );
+        assert_eq!(add.memory, MemoryJournal::default());
+    }
+}
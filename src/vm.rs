@@ -0,0 +1,409 @@
+use crate::assembler::AssembleError;
+use crate::console::Console;
+use crate::cpu::{CPUError, ExceptionMode, RunOutcome, TrapMode, CPU};
+use crate::device::Device;
+use crate::flags::ConditionFlags;
+use crate::memory::MemoryError;
+use crate::os;
+use crate::snapshot::SnapshotError;
+use crate::step::StepOutcome;
+use crate::trace::Tracer;
+use crate::undo::{UndoLog, UndoRecord};
+use std::{fs, ops::RangeInclusive, path::Path, time::Duration};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum VmError {
+    #[error("Problem reading the file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Error loading program: {0}")]
+    Memory(#[from] MemoryError),
+    #[error("Error running program: {0}")]
+    Cpu(#[from] CPUError),
+    #[error("Error assembling the operating system: {0}")]
+    Os(#[from] AssembleError),
+    #[error("Error loading the snapshot: {0}")]
+    Snapshot(#[from] SnapshotError),
+}
+
+/// An LC-3 machine: the CPU together with the memory it owns.
+pub struct Vm {
+    cpu: CPU,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self { cpu: CPU::new() }
+    }
+
+    pub fn with_console(console: impl Console + 'static) -> Self {
+        Self {
+            cpu: CPU::with_console(Box::new(console)),
+        }
+    }
+
+    pub fn from_cpu(cpu: CPU) -> Self {
+        Self { cpu }
+    }
+
+    /// Loads an image whose first word is the origin address.
+    pub fn load_program(&mut self, data: &[u16]) -> Result<(), VmError> {
+        self.cpu.memory.load_program(data)?;
+        Ok(())
+    }
+
+    /// Loads the contents of an `.obj` file: big-endian words, origin first.
+    pub fn load_obj(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.load_program(&words_from_obj(bytes))
+    }
+
+    pub fn load_obj_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), VmError> {
+        let bytes = fs::read(path)?;
+        self.load_obj(&bytes)
+    }
+
+    /// Maps a peripheral's registers at `range`, in front of RAM.
+    pub fn map_device(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: impl Device + 'static,
+    ) -> Result<(), VmError> {
+        self.cpu.memory.map_device(range, Box::new(device))?;
+        Ok(())
+    }
+
+    /// Removes the device mapped at `address`, e.g. to map a differently
+    /// configured one.
+    pub fn unmap_device(&mut self, address: u16) -> Option<Box<dyn Device>> {
+        self.cpu.memory.unmap_device(address)
+    }
+
+    /// Loads the bundled operating system into system space and resets the
+    /// CPU to boot it in supervisor mode. Traps then go through its routines
+    /// and exceptions through its handlers; its boot code enters the user
+    /// program at x3000.
+    pub fn load_os(&mut self) -> Result<(), VmError> {
+        let image = os::image()?;
+        self.load_program(&image.to_words())?;
+        self.cpu.pc = os::BOOT_ADDRESS;
+        self.cpu.psr = ConditionFlags::ZRO.into();
+        self.cpu.trap_mode = TrapMode::Exception;
+        self.cpu.exception_mode = ExceptionMode::Strict;
+        Ok(())
+    }
+
+    /// The whole machine state: registers, memory and devices.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    pub fn save_state_file<P: AsRef<Path>>(&self, path: P) -> Result<(), VmError> {
+        fs::write(path, self.save_state())?;
+        Ok(())
+    }
+
+    /// Replaces the machine state with a snapshot from `save_state`.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.cpu.load_state(bytes)?;
+        Ok(())
+    }
+
+    pub fn load_state_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), VmError> {
+        let bytes = fs::read(path)?;
+        self.load_state(&bytes)
+    }
+
+    /// Fetches, decodes and executes a single instruction.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        Ok(self.cpu.step()?)
+    }
+
+    /// Runs until the program halts or the timeout elapses.
+    pub fn run(&mut self) -> Result<RunOutcome, VmError> {
+        Ok(self.cpu.execute_program()?)
+    }
+
+    /// Runs at most `max_instructions` instructions.
+    pub fn run_for(&mut self, max_instructions: u64) -> Result<RunOutcome, VmError> {
+        Ok(self.cpu.run_for(max_instructions)?)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.cpu.timeout = timeout;
+    }
+
+    pub fn set_exception_mode(&mut self, mode: ExceptionMode) {
+        self.cpu.exception_mode = mode;
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.cpu.trap_mode = mode;
+    }
+
+    /// Keeps what the last `capacity` instructions changed so they can be
+    /// undone with `step_back`, or stops recording with `None`.
+    pub fn set_undo_capacity(&mut self, capacity: Option<usize>) {
+        self.cpu.undo_log = capacity.map(UndoLog::new);
+    }
+
+    /// Instructions that can currently be undone.
+    pub fn undo_depth(&self) -> usize {
+        self.cpu.undo_log.as_ref().map_or(0, UndoLog::len)
+    }
+
+    /// Undoes the last executed instruction, returning what it changed.
+    pub fn step_back(&mut self) -> Result<Option<UndoRecord>, VmError> {
+        Ok(self.cpu.step_back()?)
+    }
+
+    /// Logs every instruction executed from now on, or stops logging with `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.cpu.tracer = tracer;
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.cpu.instruction_count
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.cpu.running
+    }
+
+    /// Returns R0..R7 for indexes 0..7 and the PC for index 8.
+    pub fn register(&self, index: u16) -> Result<u16, VmError> {
+        Ok(self.cpu.get_register_value(index)?)
+    }
+
+    pub fn set_register(&mut self, index: u16, value: u16) -> Result<(), VmError> {
+        self.cpu.update_register(index, value)?;
+        Ok(())
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.pc = pc;
+    }
+
+    pub fn cond(&self) -> u16 {
+        self.cpu.cond()
+    }
+
+    pub fn psr(&self) -> u16 {
+        self.cpu.psr
+    }
+
+    /// Reads a memory cell without triggering device side effects.
+    pub fn read_memory(&self, address: u16) -> u16 {
+        self.cpu.memory.peek(address)
+    }
+
+    pub fn write_memory(&mut self, address: u16, value: u16) -> Result<(), VmError> {
+        self.cpu.memory.write(address, value)?;
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+}
+
+pub fn words_from_obj(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|two_bytes| match two_bytes {
+            [first_byte, second_byte] => u16::from_be_bytes([*first_byte, *second_byte]),
+            _ => 0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::console::BufferedConsole;
+
+    #[test]
+    fn test_words_from_obj() {
+        let words = words_from_obj(&[0x30, 0x00, 0x12, 0x34, 0xFF]);
+        assert_eq!(words, vec![0x3000, 0x1234]);
+    }
+
+    #[test]
+    fn test_load_and_run() {
+        let mut vm = Vm::new();
+        // ADD R0, R0, #5 ; HALT
+        vm.load_obj(&[0x30, 0x00, 0x10, 0x25, 0xF0, 0x25]).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.register(0).unwrap(), 5);
+        assert_eq!(vm.pc(), 0x3002);
+        assert!(!vm.is_running());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = Vm::new();
+        vm.load_program(&[0x3000, 0x1025, 0x1025]).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.register(0).unwrap(), 5);
+        assert_eq!(vm.pc(), 0x3001);
+        assert!(vm.is_running());
+    }
+
+    #[test]
+    fn test_buffered_console() {
+        let console = BufferedConsole::with_input("z");
+        let mut vm = Vm::with_console(console.clone());
+        // GETC ; OUT ; HALT
+        vm.load_program(&[0x3000, 0xF020, 0xF021, 0xF025]).unwrap();
+        vm.run().unwrap();
+        assert_eq!(console.output_string(), "z");
+    }
+
+    #[test]
+    fn test_memory_accessors() {
+        let mut vm = Vm::new();
+        vm.write_memory(0x4000, 0xBEEF).unwrap();
+        assert_eq!(vm.read_memory(0x4000), 0xBEEF);
+    }
+
+    #[test]
+    fn test_load_empty_program() {
+        let mut vm = Vm::new();
+        assert!(matches!(
+            vm.load_program(&[]),
+            Err(VmError::Memory(MemoryError::EmptyOrigin))
+        ));
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, FIRST
+            PUTS
+            LEA R0, SECOND
+            PUTS
+            HALT
+    FIRST   .STRINGZ "Hi "
+    SECOND  .STRINGZ "again"
+            .END
+        "#;
+        let (mut vm, console) = boot_os(source, "");
+        // Stop in the middle of the user program, after the first PUTS
+        while console.output_string() != "Hi " {
+            vm.step().unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("lc3-snapshot-{}.bin", std::process::id()));
+        vm.save_state_file(&path).unwrap();
+
+        let resumed_console = BufferedConsole::new();
+        let mut resumed = Vm::with_console(resumed_console.clone());
+        resumed.load_state_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.pc(), vm.pc());
+        assert_eq!(resumed.instruction_count(), vm.instruction_count());
+        assert_eq!(resumed.save_state(), vm.save_state());
+
+        resumed.run().unwrap();
+        assert_eq!(
+            resumed_console.output_string(),
+            "again\n\n--- Halting the LC-3 ---\n\n"
+        );
+        assert!(matches!(
+            resumed.load_state(b"LC3S"),
+            Err(VmError::Snapshot(SnapshotError::Truncated))
+        ));
+    }
+
+    fn boot_os(source: &str, input: &str) -> (Vm, BufferedConsole) {
+        let console = BufferedConsole::with_input(input);
+        let mut vm = Vm::with_console(console.clone());
+        vm.load_os().unwrap();
+        let program = crate::assembler::assemble(source).unwrap();
+        vm.load_program(&program.to_words()).unwrap();
+        (vm, console)
+    }
+
+    #[test]
+    fn test_os_services_traps_in_user_mode() {
+        let source = r#"
+            .ORIG x3000
+            ADD R2, R2, #7
+            LEA R0, HELLO
+            PUTS
+            LEA R0, PACKED
+            PUTSP
+            IN
+            OUT
+            HALT
+    HELLO   .STRINGZ "Hi "
+    PACKED  .FILL x6B6F
+            .FILL x0021
+            .FILL x0000
+            .END
+        "#;
+        let (mut vm, console) = boot_os(source, "q");
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        assert_eq!(
+            console.output_string(),
+            "Hi ok!\nInput a character> q\nq\n\n--- Halting the LC-3 ---\n\n"
+        );
+        assert_eq!(vm.register(2).unwrap(), 7);
+        assert_eq!(vm.cpu().saved_ssp, 0x3000);
+    }
+
+    #[test]
+    fn test_os_services_traps_in_supervisor_mode() {
+        let source = r#"
+            .ORIG x3000
+            LEA R0, HELLO
+            PUTS
+            ADD R2, R6, #0
+            HALT
+    HELLO   .STRINGZ "Hi"
+            .END
+        "#;
+        let (mut vm, console) = boot_os(source, "");
+        // Skip the boot code, so the program runs privileged on the
+        // supervisor stack
+        vm.set_pc(0x3000);
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        assert_eq!(
+            console.output_string(),
+            "Hi\n\n--- Halting the LC-3 ---\n\n"
+        );
+        assert_eq!(vm.register(2).unwrap(), 0x3000);
+        assert!(!vm.cpu().is_user_mode());
+    }
+
+    #[test]
+    fn test_os_reports_exceptions() {
+        let (mut vm, console) = boot_os(".ORIG x3000\n.FILL xD000\n.END", "");
+
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        assert_eq!(
+            console.output_string(),
+            "\n\n--- Illegal opcode ---\n\n\n--- Halting the LC-3 ---\n\n"
+        );
+    }
+}